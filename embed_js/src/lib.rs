@@ -1,8 +1,11 @@
 //! This crate allows you to embed inline javascript in your Rust code for use with the
-//! wasm32-unknown-unknown target. Crates also need to use the `embed_js_build` crate in their
-//! build script (see that crate's documentation for more details). Crates that generate binaries
-//! must also use `embed_js_build` in a post-build step to collect the generated accompanying
-//! javascript from itself and all dependencies.
+//! wasm32-unknown-unknown target. Crates that use the `include_js` or `include_js_file` macros also
+//! need to use the `embed_js_build` crate in their build script (see that crate's documentation for
+//! more details). Crates that generate binaries must use `embed_js_build` in a post-build step to
+//! collect the generated accompanying javascript from itself and all dependencies.
+//!
+//! The macros can be brought into scope with `#[macro_use] extern crate embed_js;`, imported by
+//! `use` declarations such as `use embed_js::js;`, or called by path as `embed_js::js!`.
 //!
 //! See the documentation pages of the macros in this crate for more details and examples. The
 //! embed_js repository also contains example projects.
@@ -14,8 +17,31 @@ extern crate embed_js_derive;
 #[doc(hidden)]
 pub use embed_js_derive::*;
 
-/// For every crate that uses the `js` macro directly, the `embed_js_preamble` macro must be called
-/// at least once somewhere in the crate, for example
+use std::alloc::{ self, Layout };
+use std::mem;
+use std::ops::Deref;
+use std::ptr;
+
+/// Allocates `size` bytes for the JavaScript side of a `js` macro call to fill in and hand back to
+/// Rust as a `String` or `Vec<u8>` with a capacity of `size`. Exported as
+/// `wasm_exports.__embed_js_alloc`.
+#[doc(hidden)]
+#[no_mangle]
+pub extern "C" fn __embed_js_alloc(size: usize) -> *mut u8 {
+    // allocating zero bytes is undefined, and an empty `String` or `Vec` needs no memory
+    if size == 0 {
+        return ptr::dangling_mut();
+    }
+    let layout = Layout::array::<u8>(size).unwrap();
+    let ptr = unsafe { alloc::alloc(layout) };
+    if ptr.is_null() {
+        alloc::handle_alloc_error(layout);
+    }
+    ptr
+}
+
+/// For every crate that uses the `include_js` or `include_js_file` macros directly, the
+/// `embed_js_preamble` macro must be called at least once somewhere in the crate, for example
 ///
 /// ```ignore
 /// #[macro_use]
@@ -23,14 +49,19 @@ pub use embed_js_derive::*;
 ///
 /// embed_js_preamble!();
 ///
+/// include_js! {
+///     window.greet = function() {
+///         console.log("Hello world!");
+///     };
+/// }
+///
 /// #[no_mangle]
 /// pub fn entry_point() {
-///     js!({console.log("Hello world!");});
+///     js!({greet();});
 /// }
 /// ```
 ///
-/// You do not need to import `embed_js` at all in crates that only depend on crates that use the
-/// `js` macro without directly calling it themselves.
+/// Crates that only use the `js` macro do not need to call this macro or have a build script.
 #[macro_export]
 macro_rules! embed_js_preamble {
     () => { include!(concat!(env!("OUT_DIR"), "/embed_js_preamble.rs")); }
@@ -38,12 +69,16 @@ macro_rules! embed_js_preamble {
 
 /// Call javascript, inline.
 ///
-/// This macro must not be called from within any other macro, otherwise it will fail to work.
+/// This macro may be called from within other macros, such as your own `macro_rules!` wrappers.
 ///
-/// The javascript written inside calls to this macro must adhere to some additional rules:
+/// The javascript is used exactly as written between the braces, including comments and line
+/// breaks. Since it must still be made of valid Rust tokens, single-quote strings of more than one
+/// character are not allowed, but such javascript can be given as a string literal instead, as
+/// described below.
 ///
-/// * Every statement must end in a semi-colon.
-/// * No single-quote multi-character strings are allowed.
+/// If the javascript is not written out in the source as it is, such as when it is written in a
+/// `macro_rules!` wrapper and uses the wrapper's metavariables, it is instead rebuilt from its
+/// tokens without comments or line breaks, and every statement must then end in a semi-colon.
 ///
 /// There are three forms for calling this macro:
 ///
@@ -60,9 +95,48 @@ macro_rules! embed_js_preamble {
 ///   Other arguments take the form of a possibly dereferenced identifier followed by `as type`,
 ///   for some type `type`. Values are cast using `as` to this type before passing to the JavaScript.
 ///
-///   Every type specified, including the return type, must be one of `i32`, `i64`,
-///   `f32` or `f64`. These are the only raw types supported by WebAssembly for interop at the
-///   moment. More complicated types are best passed by reference.
+///   Any Rust expression can be passed by naming it for the JavaScript with `=>`, as in
+///   `self.width => width as i32`, `v.len() => len as i32`, `self.title => title: &str` or
+///   `&point => p`. The expression is evaluated and passed as an argument of the form written after
+///   the `=>`, so `v.len() => len as i32` passes `v.len() as i32` as `len`. Without a type, the
+///   expression must be a reference, written starting with `&` or `&mut`.
+///
+///   A name may be a raw identifier such as `r#type`, which the JavaScript sees as `type`. Names
+///   that are reserved in JavaScript, such as `new`, `in` or `class`, are rejected, so a value held
+///   in a variable of such a name is passed under another one, as in `r#new => created as i32`.
+///
+///   Arguments of the form `name: &str` (or equivalently `name: String`) are strings. They accept
+///   a `&str`, `String` or `&String`, which is passed to the JavaScript as a pointer and a length
+///   and decoded, so that `name` is an ordinary JavaScript string by the time the body runs.
+///
+///   Arguments of the form `name: &[T]` or `name: &mut [T]` are slices, where `T` is one of `i8`,
+///   `u8`, `i16`, `u16`, `i32`, `u32`, `isize`, `usize`, `i64`, `f32` or `f64`. They accept
+///   anything that can be sliced with `[..]`, such as a `Vec<T>`, an array or a slice, and are
+///   passed as a pointer and a length, so that `name` is a typed array such as a `Float32Array`
+///   viewing the elements in the wasm memory, without copying them. Writes to the view of a
+///   `&mut` slice change the elements. The view stops working if the wasm memory grows, so it
+///   should not be kept after the body returns.
+///
+///   Arguments of the form `name: JsRef` accept a `JsRef` or `&JsRef`, and are passed to the
+///   JavaScript as the value the `JsRef` refers to. The `JsRef` is not consumed. Likewise,
+///   arguments of the form `name: Closure` accept a `Closure` or `&Closure`, and are passed as a
+///   JavaScript function that calls it.
+///
+///   Every other type specified, including the return type, must be one of `i32`, `i64`, `f32`,
+///   `f64`, `bool`, `i8`, `i16`, `u8`, `u16`, `u32`, `isize`, `usize`, `char` or a raw pointer
+///   type such as `*const T` or `*mut T`, where `T` is sized. Each is passed as one of the raw
+///   types supported by WebAssembly for interop, and the JavaScript sees its natural value: `bool`
+///   is `true` or `false`, `char` a string of one code point, and unsigned integers and pointers
+///   non-negative numbers, pointers being addresses in the wasm memory. An `i64` is a `BigInt`,
+///   passed in two halves or natively as chosen with `embed_js_build::PostProcessOptions::i64_mode`.
+///   Values returned from the JavaScript are converted back in the same way, and an `i64` may also
+///   be returned as an integral number. More complicated types are best passed by reference.
+///
+///   The return type may also be `String`, `Vec<u8>` or `JsRef`. For `String` the JavaScript should return
+///   a string, and for `Vec<u8>` anything a `Uint8Array` can be constructed from, such as a typed
+///   array, an `ArrayBuffer` or an array of numbers. The value is copied into memory allocated by
+///   this crate and handed to Rust as an owned value. For `JsRef` the JavaScript may return any
+///   value, which is kept alive until the returned `JsRef` is dropped.
 ///
 ///   Examples:
 ///
@@ -75,6 +149,51 @@ macro_rules! embed_js_preamble {
 ///   ```
 ///
 ///   ```ignore
+///   let size = (640, 480);
+///   let area = js!([size.0 => w as i32, size.1 => h as i32] -> i32 {
+///       return w * h;
+///   });
+///   ```
+///
+///   ```ignore
+///   let name = String::from("World");
+///   js!([name: &str] {
+///       console.log("Hello " + name + "!");
+///   });
+///   ```
+///
+///   ```ignore
+///   let mut vertices = vec![0.0f32; 6];
+///   js!([vertices: &mut [f32]] {
+///       vertices.set([0, 1, -1, -1, 1, -1]);
+///   });
+///   js!([vertices: &[f32]] {
+///       gl.bufferData(gl.ARRAY_BUFFER, vertices, gl.STATIC_DRAW);
+///   });
+///   ```
+///
+///   ```ignore
+///   let title = js!([] -> String {
+///       return document.title;
+///   });
+///   ```
+///
+///   ```ignore
+///   let body = js!([] -> JsRef {
+///       return document.body;
+///   });
+///   js!([body: JsRef] {
+///       body.appendChild(document.createTextNode("Hello!"));
+///   });
+///   ```
+///
+///   ```ignore
+///   let hidden = js!([] -> bool {
+///       return document.hidden;
+///   });
+///   ```
+///
+///   ```ignore
 ///   let one_half = js!([] -> f64 {
 ///       return 1.0 / 2.0;
 ///   });
@@ -88,26 +207,348 @@ macro_rules! embed_js_preamble {
 /// * `js!({ /*javascript*/ })`
 ///
 ///   No arguments or return type.
+///
+/// In each form the braced javascript may be replaced with a string literal, usually a raw string,
+/// whose contents are used as the javascript as they are. This allows javascript that is not made
+/// of valid Rust tokens, such as single-quoted strings, template literals, regular expressions and
+/// `\u0041` escapes:
+///
+/// ```ignore
+/// let n = js!([] -> i32 r#"
+///     return '42'.match(/\d+/g).length;
+/// "#);
+/// ```
+///
+/// The javascript may also be read from a file with `include_str!`, which finds the file relative
+/// to the current file in the same way as the standard macro of that name:
+///
+/// ```ignore
+/// let n = js!([x as i32] -> i32 include_str!("double.js"));
+/// ```
 #[macro_export]
 macro_rules! js {
     ([$($args:tt)*] $($tt:tt)*) => {{
-        #[derive(EmbedJsDetail)]
+        #[allow(unused_imports)]
+        use $crate::JsRef as EmbedJsRef;
+        #[derive($crate::EmbedJsDetail)]
         #[allow(dead_code)]
         enum EmbedJsStruct {
             Input = (stringify!([$($args)*] $($tt)*), 0).1
         }
-        EmbedJsStruct::call($($args)*)
+        $crate::__embed_js_call!([] $($args)*)
+    }};
+    (include_str! $path:tt) => {{
+        #[derive($crate::EmbedJsDetail)]
+        #[allow(dead_code)]
+        enum EmbedJsStruct {
+            Input = (stringify!(include_str! $path), 0).1
+        }
+        EmbedJsStruct::call()
+    }};
+    ($body:literal) => {{
+        #[derive($crate::EmbedJsDetail)]
+        #[allow(dead_code)]
+        enum EmbedJsStruct {
+            Input = (stringify!($body), 0).1
+        }
+        EmbedJsStruct::call()
     }};
-    ({$($tt:tt)*}) => {{
-        #[derive(EmbedJsDetail)]
+    // the body is passed on as a single token tree so that it keeps its span, and so its source text
+    ($body:tt) => {{
+        #[derive($crate::EmbedJsDetail)]
         #[allow(dead_code)]
         enum EmbedJsStruct {
-            Input = (stringify!({$($tt)*}), 0).1
+            Input = (stringify!($body), 0).1
         }
         EmbedJsStruct::call()
     }};
 }
 
+/// Implementation detail of the `js` macro. Converts the argument list of a `js` call into the
+/// arguments to pass to the generated `call` function, one argument at a time, and passes them to
+/// the `__embed_js_invoke` macro generated along with it, which drops them if the call is malformed.
+/// Arguments that match none of the rules are passed on as they are, for the error reported by
+/// `EmbedJsDetail`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __embed_js_call {
+    ([$($done:tt)*]) => {
+        __embed_js_invoke!($($done)*)
+    };
+    ([$($done:tt)*] $name:ident : &str $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &*$name,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $name:ident : String $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &*$name,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $name:ident : JsRef $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$name,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $name:ident : Closure $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$name,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $name:ident : &[$elem:ident] $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$name[..],] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $name:ident : &mut [$elem:ident] $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &mut $name[..],] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident as $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* $e as $ty,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : &str $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &*$e,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : String $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &*$e,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : JsRef $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$e,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : Closure $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$e,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : &[$elem:ident] $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &$e[..],] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident : &mut [$elem:ident] $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* &mut $e[..],] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $e:expr => $name:ident $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* $e,] $($($rest)*)?)
+    };
+    // `name as type`, `*name as type` and references such as `&mut name` are expressions already
+    ([$($done:tt)*] $e:expr $(, $($rest:tt)*)?) => {
+        $crate::__embed_js_call!([$($done)* $e,] $($($rest)*)?)
+    };
+    ([$($done:tt)*] $($rest:tt)*) => {
+        __embed_js_invoke!($($done)* $($rest)*)
+    };
+}
+
+/// An owned handle to a JavaScript value.
+///
+/// The value is kept in a table on the JavaScript side, set up by the javascript that
+/// `embed_js_build::postprocess_crate` puts in `included`, and `JsRef` holds its index in that
+/// table. Dropping a `JsRef` releases its slot in the table, and cloning one gives a new slot
+/// referring to the same value.
+///
+/// A `JsRef` is obtained by returning a value from a `js` macro call with a `JsRef` return type,
+/// and is passed back into JavaScript as an argument of the form `name: JsRef`. Handles are not
+/// comparable in Rust, since two of them may refer to the same value from different slots; compare
+/// the values in JavaScript instead, as in `js!([a: JsRef, b: JsRef] -> bool { return a === b; })`.
+#[derive(Debug)]
+pub struct JsRef {
+    id: u32
+}
+
+impl JsRef {
+    /// Creates a `JsRef` from an index in the JavaScript table, taking ownership of the slot.
+    ///
+    /// # Safety
+    ///
+    /// `id` must be a slot in the table that is not owned by any other `JsRef`, such as one
+    /// returned by `into_raw`.
+    pub unsafe fn from_raw(id: u32) -> JsRef {
+        JsRef { id }
+    }
+
+    /// The index of the value in the JavaScript table. From JavaScript, the value can be looked
+    /// up with `embed_js_heap.get(id)`.
+    pub fn as_raw(&self) -> u32 {
+        self.id
+    }
+
+    /// Gives up ownership of the slot in the JavaScript table without releasing it, returning its
+    /// index.
+    pub fn into_raw(self) -> u32 {
+        let id = self.id;
+        mem::forget(self);
+        id
+    }
+}
+
+impl Clone for JsRef {
+    fn clone(&self) -> JsRef {
+        let id = self.id;
+        let id = js!([id as i32] -> i32 {
+            return embed_js_heap.clone(id);
+        });
+        JsRef { id: id as u32 }
+    }
+}
+
+impl Drop for JsRef {
+    fn drop(&mut self) {
+        let id = self.id;
+        js!([id as i32] {
+            embed_js_heap.drop(id);
+        });
+    }
+}
+
+/// A Rust closure that can be called from JavaScript.
+///
+/// Passed to a `js` macro call as an argument of the form `name: Closure`, it arrives in the
+/// JavaScript as a function. Calling the function calls the closure with the arguments converted
+/// to the closure's argument types, and returns the closure's return value. The closure's argument
+/// types must each be one of `i32`, `f32` or `f64`, as must its return type unless it returns
+/// nothing. `i64` is not supported, since the function is called through the function table,
+/// where the halves that `embed_js_build::I64Mode::Split` passes instead cannot be put back
+/// together.
+///
+/// The closure is dropped when the `Closure` is dropped, or when the JavaScript calls the `drop`
+/// method of the function, whichever comes first. If the function is called after that, it throws
+/// an error instead. To keep the closure alive for as long as the JavaScript holds onto the
+/// function, for example for an event handler, use `forget`.
+///
+/// ```ignore
+/// let mut clicks = 0;
+/// let on_click = Closure::new(move || {
+///     clicks += 1;
+/// });
+/// js!([on_click: Closure] {
+///     document.body.addEventListener("click", on_click);
+/// });
+/// on_click.forget();
+/// ```
+#[derive(Debug)]
+pub struct Closure {
+    func: JsRef
+}
+
+impl Closure {
+    /// Wraps a closure that may be called any number of times.
+    ///
+    /// The function given to the JavaScript throws an error if it is called again while the
+    /// closure is already running.
+    pub fn new<F, Args>(f: F) -> Closure
+        where F: ClosureFnMut<Args>
+    {
+        let (call, data, drop) = f.into_parts();
+        Closure::from_parts(call, data, drop, false)
+    }
+
+    /// Wraps a closure that may be called at most once. It is dropped after it is called.
+    pub fn once<F, Args>(f: F) -> Closure
+        where F: ClosureFnOnce<Args>
+    {
+        let (call, data, drop) = f.into_parts();
+        Closure::from_parts(call, data, drop, true)
+    }
+
+    fn from_parts(call: usize, data: *mut (), drop: usize, once: bool) -> Closure {
+        let data = data as usize;
+        let func = js!([call as i32, data as i32, drop as i32, once as i32] -> JsRef {
+            return embed_js_closure(call, data, drop, once);
+        });
+        Closure { func }
+    }
+
+    /// Leaves the closure alive until the JavaScript calls the `drop` method of the function, if it
+    /// ever does.
+    pub fn forget(self) {
+        let func = unsafe { ptr::read(&self.func) };
+        mem::forget(self);
+        func.into_raw();
+    }
+}
+
+/// Gives the `JsRef` of the JavaScript function.
+impl Deref for Closure {
+    type Target = JsRef;
+    fn deref(&self) -> &JsRef {
+        &self.func
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        let func = &self.func;
+        js!([func: JsRef] {
+            func.drop();
+        });
+    }
+}
+
+/// Types that a `Closure` can take as arguments: `i32`, `f32` and `f64`.
+pub trait ClosureArg: Copy + 'static {}
+
+/// Types that a `Closure` can return: `()`, `i32`, `f32` and `f64`.
+pub trait ClosureRet: 'static {}
+
+impl ClosureArg for i32 {}
+impl ClosureArg for f32 {}
+impl ClosureArg for f64 {}
+
+impl ClosureRet for () {}
+impl ClosureRet for i32 {}
+impl ClosureRet for f32 {}
+impl ClosureRet for f64 {}
+
+/// Implemented for `FnMut` closures that can be wrapped with `Closure::new`. `Args` is a tuple of
+/// the closure's argument types.
+pub trait ClosureFnMut<Args>: 'static {
+    #[doc(hidden)]
+    fn into_parts(self) -> (usize, *mut (), usize);
+}
+
+/// Implemented for `FnOnce` closures that can be wrapped with `Closure::once`. `Args` is a tuple
+/// of the closure's argument types.
+pub trait ClosureFnOnce<Args>: 'static {
+    #[doc(hidden)]
+    fn into_parts(self) -> (usize, *mut (), usize);
+}
+
+// Both kinds of closure are boxed as an `Option<F>`, so that a `FnOnce` closure can be taken out
+// when it is called. The parts are the function table indices of the call and drop functions, and
+// the pointer to the box.
+macro_rules! closure_impls {
+    ($($arg:ident $name:ident),*) => {
+        impl<F, R, $($arg),*> ClosureFnMut<($($arg,)*)> for F
+            where F: FnMut($($arg),*) -> R + 'static, R: ClosureRet, $($arg: ClosureArg),*
+        {
+            fn into_parts(self) -> (usize, *mut (), usize) {
+                unsafe extern "C" fn call<F, R, $($arg),*>(data: *mut () $(, $name: $arg)*) -> R
+                    where F: FnMut($($arg),*) -> R
+                {
+                    (*(data as *mut Option<F>)).as_mut().unwrap()($($name),*)
+                }
+                let data = Box::into_raw(Box::new(Some(self))) as *mut ();
+                let call = call::<F, R, $($arg),*> as unsafe extern "C" fn(*mut () $(, $arg)*) -> R;
+                (call as usize, data, drop_closure::<F> as unsafe extern "C" fn(*mut ()) as usize)
+            }
+        }
+
+        impl<F, R, $($arg),*> ClosureFnOnce<($($arg,)*)> for F
+            where F: FnOnce($($arg),*) -> R + 'static, R: ClosureRet, $($arg: ClosureArg),*
+        {
+            fn into_parts(self) -> (usize, *mut (), usize) {
+                unsafe extern "C" fn call<F, R, $($arg),*>(data: *mut () $(, $name: $arg)*) -> R
+                    where F: FnOnce($($arg),*) -> R
+                {
+                    (*(data as *mut Option<F>)).take().unwrap()($($name),*)
+                }
+                let data = Box::into_raw(Box::new(Some(self))) as *mut ();
+                let call = call::<F, R, $($arg),*> as unsafe extern "C" fn(*mut () $(, $arg)*) -> R;
+                (call as usize, data, drop_closure::<F> as unsafe extern "C" fn(*mut ()) as usize)
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn drop_closure<F>(data: *mut ()) {
+    drop(Box::from_raw(data as *mut Option<F>));
+}
+
+closure_impls!();
+closure_impls!(A a);
+closure_impls!(A a, B b);
+closure_impls!(A a, B b, C c);
+closure_impls!(A a, B b, C c, D d);
+closure_impls!(A a, B b, C c, D d, E e);
+closure_impls!(A a, B b, C c, D d, E e, G g);
+
 /// Used to specify JavaScript that should be executed before the WebAssembly module is loaded.
 /// This is useful for specifying functions that can be shared between instances of inline JS, or
 /// set up other global state. This macro is used as a statement or at item level.
@@ -127,4 +568,23 @@ macro_rules! js {
 #[macro_export]
 macro_rules! include_js {
     ($($tt:tt)*) => {}
+}
+
+/// Include javascript from a file in the accompanying javascript output, in the same way as
+/// `include_js`. The file is found relative to the current file, in the same way as `include!`.
+///
+/// This is useful for sharing helper libraries written in plain javascript, which need not be made
+/// of valid Rust tokens. As with `include_js`, the crate must call `embed_js_preamble` and use
+/// `embed_js_build` in its build script.
+///
+/// Example:
+///
+/// ```ignore
+/// include_js_file!("helpers.js");
+/// ```
+#[macro_export]
+macro_rules! include_js_file {
+    ($path:expr) => {
+        const _: &str = include_str!($path);
+    };
 }
\ No newline at end of file
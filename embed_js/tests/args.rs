@@ -0,0 +1,51 @@
+#[macro_use]
+extern crate embed_js;
+
+use embed_js::JsRef;
+
+fn two<A, B>() -> usize {
+    std::mem::size_of::<(A, B)>()
+}
+
+// the js calls are only compiled, since they need the generated JavaScript to run
+#[allow(dead_code)]
+fn forty_args(a: u8) -> i32 {
+    let [a0, a1, a2, a3, a4, a5, a6, a7, a8, a9, a10, a11, a12, a13, a14, a15, a16, a17, a18, a19,
+         a20, a21, a22, a23, a24, a25, a26, a27, a28, a29, a30, a31, a32, a33, a34, a35, a36, a37, a38, a39] = [a; 40];
+    js!([a0 as i32, a1 as i32, a2 as i32, a3 as i32, a4 as i32, a5 as i32, a6 as i32, a7 as i32,
+         a8 as i32, a9 as i32, a10 as i32, a11 as i32, a12 as i32, a13 as i32, a14 as i32, a15 as i32,
+         a16 as i32, a17 as i32, a18 as i32, a19 as i32, a20 as i32, a21 as i32, a22 as i32, a23 as i32,
+         a24 as i32, a25 as i32, a26 as i32, a27 as i32, a28 as i32, a29 as i32, a30 as i32, a31 as i32,
+         a32 as i32, a33 as i32, a34 as i32, a35 as i32, a36 as i32, a37 as i32, a38 as i32, a39 as i32] -> i32 {
+        return a0 + a39;
+    })
+}
+
+#[allow(dead_code)]
+fn forty_expression_args(v: &[i16]) -> i32 {
+    js!([v[0] + 1 => a0 as i32, v[1] + 1 => a1 as i32, v[2] + 1 => a2 as i32, v[3] + 1 => a3 as i32,
+         v[4] + 1 => a4 as i32, v[5] + 1 => a5 as i32, v[6] + 1 => a6 as i32, v[7] + 1 => a7 as i32,
+         v[8] + 1 => a8 as i32, v[9] + 1 => a9 as i32, v[10] + 1 => a10 as i32, v[11] + 1 => a11 as i32,
+         v[12] + 1 => a12 as i32, v[13] + 1 => a13 as i32, v[14] + 1 => a14 as i32, v[15] + 1 => a15 as i32,
+         v[16] + 1 => a16 as i32, v[17] + 1 => a17 as i32, v[18] + 1 => a18 as i32, v[19] + 1 => a19 as i32,
+         v[20] + 1 => a20 as i32, v[21] + 1 => a21 as i32, v[22] + 1 => a22 as i32, v[23] + 1 => a23 as i32,
+         v[24] + 1 => a24 as i32, v[25] + 1 => a25 as i32, v[26] + 1 => a26 as i32, v[27] + 1 => a27 as i32,
+         v[28] + 1 => a28 as i32, v[29] + 1 => a29 as i32, v[30] + 1 => a30 as i32, v[31] + 1 => a31 as i32,
+         v[32] + 1 => a32 as i32, v[33] + 1 => a33 as i32, v[34] + 1 => a34 as i32, v[35] + 1 => a35 as i32,
+         v[36] + 1 => a36 as i32, v[37] + 1 => a37 as i32, v[38] + 1 => a38 as i32, v[39] + 1 => a39 as i32] -> i32 {
+        return a0 + a39;
+    })
+}
+
+#[allow(dead_code)]
+fn every_form(x: f32, mut y: f64, s: String, bytes: Vec<u8>, mut words: Vec<u16>, mut more: Vec<u8>, r: JsRef) -> i32 {
+    let p = &mut y as *mut f64;
+    js!([x as f64, p as *const f64, s: &str, bytes: &[u8], words: &mut [u16], r: JsRef,
+         two::<u8, u16>() => n as i32, s.len() => len as u32, <Vec<(u8, u8)>>::new().len() => e as i32,
+         s.clone() => t: String, &r => q: JsRef, &mut more => b: &mut [u8], &mut y,] -> i32 {
+        return n;
+    })
+}
+
+#[test]
+fn args_compile() {}
@@ -0,0 +1,216 @@
+//! Evaluation of `cfg` and `cfg_attr` attributes in the configuration cargo describes to build
+//! scripts.
+//!
+//! Configuration options are read from the `CARGO_CFG_*` environment variables and features from
+//! the `CARGO_FEATURE_*` ones. Options that cargo does not pass on, such as `test`, are never set.
+
+use syn::*;
+use syn::parse::ParseStream;
+use syn::punctuated::Punctuated;
+
+use std::env;
+
+/// Syntax that can have attributes.
+pub trait Attrs {
+    fn attrs(&self) -> &[Attribute];
+}
+
+impl Attrs for Item {
+    fn attrs(&self) -> &[Attribute] {
+        match *self {
+            Item::Const(ItemConst { ref attrs, .. })
+            | Item::Enum(ItemEnum { ref attrs, .. })
+            | Item::ExternCrate(ItemExternCrate { ref attrs, .. })
+            | Item::Fn(ItemFn { ref attrs, .. })
+            | Item::ForeignMod(ItemForeignMod { ref attrs, .. })
+            | Item::Impl(ItemImpl { ref attrs, .. })
+            | Item::Macro(ItemMacro { ref attrs, .. })
+            | Item::Mod(ItemMod { ref attrs, .. })
+            | Item::Static(ItemStatic { ref attrs, .. })
+            | Item::Struct(ItemStruct { ref attrs, .. })
+            | Item::Trait(ItemTrait { ref attrs, .. })
+            | Item::TraitAlias(ItemTraitAlias { ref attrs, .. })
+            | Item::Type(ItemType { ref attrs, .. })
+            | Item::Union(ItemUnion { ref attrs, .. })
+            | Item::Use(ItemUse { ref attrs, .. }) => attrs,
+            _ => &[],
+        }
+    }
+}
+
+impl Attrs for ImplItem {
+    fn attrs(&self) -> &[Attribute] {
+        match *self {
+            ImplItem::Const(ImplItemConst { ref attrs, .. })
+            | ImplItem::Fn(ImplItemFn { ref attrs, .. })
+            | ImplItem::Type(ImplItemType { ref attrs, .. })
+            | ImplItem::Macro(ImplItemMacro { ref attrs, .. }) => attrs,
+            _ => &[],
+        }
+    }
+}
+
+impl Attrs for TraitItem {
+    fn attrs(&self) -> &[Attribute] {
+        match *self {
+            TraitItem::Const(TraitItemConst { ref attrs, .. })
+            | TraitItem::Fn(TraitItemFn { ref attrs, .. })
+            | TraitItem::Type(TraitItemType { ref attrs, .. })
+            | TraitItem::Macro(TraitItemMacro { ref attrs, .. }) => attrs,
+            _ => &[],
+        }
+    }
+}
+
+impl Attrs for Expr {
+    fn attrs(&self) -> &[Attribute] {
+        match *self {
+            Expr::Array(ExprArray { ref attrs, .. })
+            | Expr::Assign(ExprAssign { ref attrs, .. })
+            | Expr::Async(ExprAsync { ref attrs, .. })
+            | Expr::Await(ExprAwait { ref attrs, .. })
+            | Expr::Binary(ExprBinary { ref attrs, .. })
+            | Expr::Block(ExprBlock { ref attrs, .. })
+            | Expr::Break(ExprBreak { ref attrs, .. })
+            | Expr::Call(ExprCall { ref attrs, .. })
+            | Expr::Cast(ExprCast { ref attrs, .. })
+            | Expr::Closure(ExprClosure { ref attrs, .. })
+            | Expr::Const(ExprConst { ref attrs, .. })
+            | Expr::Continue(ExprContinue { ref attrs, .. })
+            | Expr::Field(ExprField { ref attrs, .. })
+            | Expr::ForLoop(ExprForLoop { ref attrs, .. })
+            | Expr::Group(ExprGroup { ref attrs, .. })
+            | Expr::If(ExprIf { ref attrs, .. })
+            | Expr::Index(ExprIndex { ref attrs, .. })
+            | Expr::Infer(ExprInfer { ref attrs, .. })
+            | Expr::Let(ExprLet { ref attrs, .. })
+            | Expr::Lit(ExprLit { ref attrs, .. })
+            | Expr::Loop(ExprLoop { ref attrs, .. })
+            | Expr::Macro(ExprMacro { ref attrs, .. })
+            | Expr::Match(ExprMatch { ref attrs, .. })
+            | Expr::MethodCall(ExprMethodCall { ref attrs, .. })
+            | Expr::Paren(ExprParen { ref attrs, .. })
+            | Expr::Path(ExprPath { ref attrs, .. })
+            | Expr::Range(ExprRange { ref attrs, .. })
+            | Expr::RawAddr(ExprRawAddr { ref attrs, .. })
+            | Expr::Reference(ExprReference { ref attrs, .. })
+            | Expr::Repeat(ExprRepeat { ref attrs, .. })
+            | Expr::Return(ExprReturn { ref attrs, .. })
+            | Expr::Struct(ExprStruct { ref attrs, .. })
+            | Expr::Try(ExprTry { ref attrs, .. })
+            | Expr::TryBlock(ExprTryBlock { ref attrs, .. })
+            | Expr::Tuple(ExprTuple { ref attrs, .. })
+            | Expr::Unary(ExprUnary { ref attrs, .. })
+            | Expr::Unsafe(ExprUnsafe { ref attrs, .. })
+            | Expr::While(ExprWhile { ref attrs, .. })
+            | Expr::Yield(ExprYield { ref attrs, .. }) => attrs,
+            _ => &[],
+        }
+    }
+}
+
+/// The attributes in `attrs`, with each `cfg_attr` replaced by the attributes it expands to in the
+/// current configuration.
+pub fn expand_attrs(attrs: &[Attribute]) -> Vec<Meta> {
+    let mut expanded = Vec::new();
+    for attr in attrs {
+        expand_attr(&attr.meta, &mut expanded);
+    }
+    expanded
+}
+
+fn expand_attr(attr: &Meta, expanded: &mut Vec<Meta>) {
+    if let Meta::List(ref list) = *attr {
+        if list.path.is_ident("cfg_attr") {
+            if let Some((predicate, attrs)) = nested(list).as_ref().and_then(|nested| nested.split_first()) {
+                if is_true(predicate) {
+                    for attr in attrs {
+                        expand_attr(attr, expanded);
+                    }
+                }
+            }
+            return;
+        }
+    }
+    expanded.push(attr.clone());
+}
+
+/// The comma-separated items in the parentheses of `list`, or `None` if they cannot be parsed. The
+/// predicates `true` and `false` are given as paths of those names.
+fn nested(list: &MetaList) -> Option<Vec<Meta>> {
+    fn item(input: ParseStream) -> Result<Meta> {
+        if input.peek(LitBool) {
+            let value: LitBool = input.parse()?;
+            Ok(Meta::Path(Ident::new(&value.value.to_string(), value.span).into()))
+        } else {
+            input.parse()
+        }
+    }
+    list.parse_args_with(|input: ParseStream| Punctuated::<Meta, Token![,]>::parse_terminated_with(input, item))
+        .ok()
+        .map(|nested| nested.into_iter().collect())
+}
+
+/// Whether something with the expanded attributes `attrs` is compiled in the current
+/// configuration.
+pub fn is_enabled(attrs: &[Meta]) -> bool {
+    attrs.iter().all(|attr| {
+        match *attr {
+            Meta::List(ref list) if list.path.is_ident("cfg") => {
+                match nested(list) {
+                    Some(ref nested) if nested.len() == 1 => is_true(&nested[0]),
+                    _ => false,
+                }
+            }
+            _ => true,
+        }
+    })
+}
+
+/// Evaluates a configuration predicate, as found in `cfg(...)`.
+fn is_true(predicate: &Meta) -> bool {
+    match *predicate {
+        Meta::Path(ref path) => {
+            match path.get_ident() {
+                Some(name) if name == "true" => true,
+                Some(name) if name == "false" => false,
+                Some(name) => is_set(&name.to_string(), None),
+                None => false,
+            }
+        }
+        Meta::NameValue(MetaNameValue { ref path, value: Expr::Lit(ExprLit { lit: Lit::Str(ref value), .. }), .. }) => {
+            path.get_ident().is_some_and(|name| is_set(&name.to_string(), Some(&value.value())))
+        }
+        Meta::NameValue(_) => false,
+        Meta::List(ref list) => {
+            let nested = match nested(list) {
+                Some(nested) => nested,
+                None => return false,
+            };
+            if list.path.is_ident("all") {
+                nested.iter().all(is_true)
+            } else if list.path.is_ident("any") {
+                nested.iter().any(is_true)
+            } else if list.path.is_ident("not") {
+                nested.len() == 1 && !is_true(&nested[0])
+            } else {
+                false
+            }
+        }
+    }
+}
+
+/// Whether the configuration option `name` is set, with `value` if given.
+fn is_set(name: &str, value: Option<&str>) -> bool {
+    if name == "feature" {
+        return match value {
+            Some(feature) => env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"))).is_some(),
+            None => false,
+        };
+    }
+    // options with several values, such as `target_feature`, have them separated by commas
+    match env::var(format!("CARGO_CFG_{}", name.to_uppercase())) {
+        Ok(values) => value.is_none_or(|value| values.split(',').any(|set| set == value)),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,327 @@
+//! Removal of unused functions, globals and types from a wasm module, including unused imports.
+//!
+//! Everything reachable from the module's exports, start function, table elements and segment
+//! offsets is kept, and the remaining items are renumbered. Nothing else about the module is
+//! changed, so the result only depends on the input.
+
+use parity_wasm::elements::{
+    Module, Section, External, Internal, Opcode, InitExpr, CustomSection,
+    Deserialize, Serialize, VarUint32, VarUint7,
+};
+
+use std::io::Cursor;
+
+/// Maps old indices of one kind of item to new ones, for the items that are kept.
+struct Remap {
+    live: Vec<bool>,
+    new_indices: Vec<Option<u32>>,
+}
+
+impl Remap {
+    fn new(live: Vec<bool>) -> Remap {
+        let mut next = 0;
+        let new_indices = live.iter()
+            .map(|&live| if live {
+                next += 1;
+                Some(next - 1)
+            } else {
+                None
+            })
+            .collect();
+        Remap { live, new_indices }
+    }
+
+    fn get(&self, index: u32) -> u32 {
+        self.new_indices[index as usize].expect("reference to a removed item")
+    }
+}
+
+/// The items found to be in use so far, with the ones still to be visited.
+struct Marks {
+    functions: Vec<bool>,
+    globals: Vec<bool>,
+    types: Vec<bool>,
+    function_queue: Vec<u32>,
+    global_queue: Vec<u32>,
+}
+
+impl Marks {
+    fn function(&mut self, index: u32) {
+        if !self.functions[index as usize] {
+            self.functions[index as usize] = true;
+            self.function_queue.push(index);
+        }
+    }
+
+    fn global(&mut self, index: u32) {
+        if !self.globals[index as usize] {
+            self.globals[index as usize] = true;
+            self.global_queue.push(index);
+        }
+    }
+
+    fn init_expr(&mut self, expr: &InitExpr) {
+        for opcode in expr.code() {
+            if let Opcode::GetGlobal(index) = *opcode {
+                self.global(index);
+            }
+        }
+    }
+}
+
+/// Removes the functions, globals and types in `module` that are not used, renumbering the rest.
+pub fn prune(module: &mut Module) {
+    // types of the imported and defined functions, in function index order
+    let mut function_types = Vec::new();
+    let mut imported_globals = 0;
+    if let Some(imports) = module.import_section() {
+        for entry in imports.entries() {
+            match *entry.external() {
+                External::Function(type_ref) => function_types.push(type_ref),
+                External::Global(_) => imported_globals += 1,
+                _ => {}
+            }
+        }
+    }
+    let imported_functions = function_types.len();
+    if let Some(functions) = module.function_section() {
+        function_types.extend(functions.entries().iter().map(|func| func.type_ref()));
+    }
+    let global_count = imported_globals + module.global_section().map(|globals| globals.entries().len()).unwrap_or(0);
+    let type_count = module.type_section().map(|types| types.types().len()).unwrap_or(0);
+
+    let mut marks = Marks {
+        functions: vec![false; function_types.len()],
+        globals: vec![false; global_count],
+        types: vec![false; type_count],
+        function_queue: Vec::new(),
+        global_queue: Vec::new(),
+    };
+    if let Some(exports) = module.export_section() {
+        for entry in exports.entries() {
+            match *entry.internal() {
+                Internal::Function(index) => marks.function(index),
+                Internal::Global(index) => marks.global(index),
+                _ => {}
+            }
+        }
+    }
+    if let Some(start) = module.start_section() {
+        marks.function(start);
+    }
+    if let Some(elements) = module.elements_section() {
+        for segment in elements.entries() {
+            marks.init_expr(segment.offset());
+            for &member in segment.members() {
+                marks.function(member);
+            }
+        }
+    }
+    if let Some(data) = module.data_section() {
+        for segment in data.entries() {
+            marks.init_expr(segment.offset());
+        }
+    }
+    loop {
+        if let Some(index) = marks.function_queue.pop() {
+            marks.types[function_types[index as usize] as usize] = true;
+            if index as usize >= imported_functions {
+                let body = &module.code_section().unwrap().bodies()[index as usize - imported_functions];
+                for opcode in body.code().elements() {
+                    match *opcode {
+                        Opcode::Call(index) => marks.function(index),
+                        Opcode::CallIndirect(type_ref, _) => marks.types[type_ref as usize] = true,
+                        Opcode::GetGlobal(index) | Opcode::SetGlobal(index) => marks.global(index),
+                        _ => {}
+                    }
+                }
+            }
+        } else if let Some(index) = marks.global_queue.pop() {
+            if index as usize >= imported_globals {
+                let entry = &module.global_section().unwrap().entries()[index as usize - imported_globals];
+                marks.init_expr(entry.init_expr());
+            }
+        } else {
+            break;
+        }
+    }
+
+    let functions = Remap::new(marks.functions);
+    let globals = Remap::new(marks.globals);
+    let types = Remap::new(marks.types);
+    rewrite(module, &functions, &globals, &types, imported_functions, imported_globals);
+}
+
+fn rewrite_init_expr(expr: &mut InitExpr, globals: &Remap) {
+    for opcode in expr.code_mut() {
+        if let Opcode::GetGlobal(ref mut index) = *opcode {
+            *index = globals.get(*index);
+        }
+    }
+}
+
+fn rewrite(module: &mut Module, functions: &Remap, globals: &Remap, types: &Remap, imported_functions: usize, imported_globals: usize) {
+    for section in module.sections_mut() {
+        match *section {
+            Section::Type(ref mut section) => {
+                let mut index = 0;
+                section.types_mut().retain(|_| {
+                    index += 1;
+                    types.live[index - 1]
+                });
+            }
+            Section::Import(ref mut section) => {
+                let mut function_index = 0;
+                let mut global_index = 0;
+                section.entries_mut().retain(|entry| {
+                    match *entry.external() {
+                        External::Function(_) => {
+                            function_index += 1;
+                            functions.live[function_index - 1]
+                        }
+                        External::Global(_) => {
+                            global_index += 1;
+                            globals.live[global_index - 1]
+                        }
+                        _ => true,
+                    }
+                });
+                for entry in section.entries_mut() {
+                    if let External::Function(ref mut type_ref) = *entry.external_mut() {
+                        *type_ref = types.get(*type_ref);
+                    }
+                }
+            }
+            Section::Function(ref mut section) => {
+                let mut index = imported_functions;
+                section.entries_mut().retain(|_| {
+                    index += 1;
+                    functions.live[index - 1]
+                });
+                for func in section.entries_mut() {
+                    let type_ref = types.get(func.type_ref());
+                    *func.type_ref_mut() = type_ref;
+                }
+            }
+            Section::Code(ref mut section) => {
+                let mut index = imported_functions;
+                section.bodies_mut().retain(|_| {
+                    index += 1;
+                    functions.live[index - 1]
+                });
+                for body in section.bodies_mut() {
+                    for opcode in body.code_mut().elements_mut() {
+                        match *opcode {
+                            Opcode::Call(ref mut index) => *index = functions.get(*index),
+                            Opcode::CallIndirect(ref mut type_ref, _) => *type_ref = types.get(*type_ref),
+                            Opcode::GetGlobal(ref mut index) | Opcode::SetGlobal(ref mut index) => *index = globals.get(*index),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Section::Global(ref mut section) => {
+                let mut index = imported_globals;
+                section.entries_mut().retain(|_| {
+                    index += 1;
+                    globals.live[index - 1]
+                });
+                for entry in section.entries_mut() {
+                    rewrite_init_expr(entry.init_expr_mut(), globals);
+                }
+            }
+            Section::Export(ref mut section) => {
+                for entry in section.entries_mut() {
+                    match *entry.internal_mut() {
+                        Internal::Function(ref mut index) => *index = functions.get(*index),
+                        Internal::Global(ref mut index) => *index = globals.get(*index),
+                        _ => {}
+                    }
+                }
+            }
+            Section::Start(ref mut index) => *index = functions.get(*index),
+            Section::Element(ref mut section) => {
+                for segment in section.entries_mut() {
+                    rewrite_init_expr(segment.offset_mut(), globals);
+                    for member in segment.members_mut() {
+                        *member = functions.get(*member);
+                    }
+                }
+            }
+            Section::Data(ref mut section) => {
+                for segment in section.entries_mut() {
+                    rewrite_init_expr(segment.offset_mut(), globals);
+                }
+            }
+            Section::Custom(ref mut section) if section.name() == "name" => rewrite_names(section, functions),
+            _ => {}
+        }
+    }
+}
+
+/// Renumbers the function and local names in the "name" section, dropping the names of removed
+/// functions. Any other kinds of names are dropped, since they may refer to removed items, as are
+/// all names if the section is malformed.
+fn rewrite_names(section: &mut CustomSection, functions: &Remap) {
+    let rewritten = rewrite_name_subsections(section.payload(), functions).unwrap_or_default();
+    *section.payload_mut() = rewritten;
+}
+
+fn rewrite_name_subsections(payload: &[u8], functions: &Remap) -> Option<Vec<u8>> {
+    let mut reader = Cursor::new(payload);
+    let mut rewritten = Vec::new();
+    while (reader.position() as usize) < payload.len() {
+        let id: u8 = VarUint7::deserialize(&mut reader).ok()?.into();
+        let size: u32 = VarUint32::deserialize(&mut reader).ok()?.into();
+        let start = reader.position() as usize;
+        let end = start + size as usize;
+        if end > payload.len() {
+            return None;
+        }
+        let contents = &payload[start..end];
+        let subsection = match id {
+            0 => Some(contents.to_vec()),
+            1 | 2 => Some(rewrite_name_map(contents, id == 2, functions)?),
+            _ => None,
+        };
+        if let Some(subsection) = subsection {
+            VarUint7::from(id).serialize(&mut rewritten).unwrap();
+            VarUint32::from(subsection.len() as u32).serialize(&mut rewritten).unwrap();
+            rewritten.extend(subsection);
+        }
+        reader.set_position(end as u64);
+    }
+    Some(rewritten)
+}
+
+/// Rewrites a map from function indices to names, or to maps of local names if `locals` is set.
+/// Returns `None` if the map is malformed.
+fn rewrite_name_map(contents: &[u8], locals: bool, functions: &Remap) -> Option<Vec<u8>> {
+    let mut reader = Cursor::new(contents);
+    let count: u32 = VarUint32::deserialize(&mut reader).ok()?.into();
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let index: u32 = VarUint32::deserialize(&mut reader).ok()?.into();
+        let start = reader.position() as usize;
+        if locals {
+            let local_count: u32 = VarUint32::deserialize(&mut reader).ok()?.into();
+            for _ in 0..local_count {
+                VarUint32::deserialize(&mut reader).ok()?;
+                String::deserialize(&mut reader).ok()?;
+            }
+        } else {
+            String::deserialize(&mut reader).ok()?;
+        }
+        let end = reader.position() as usize;
+        if (index as usize) < functions.live.len() && functions.live[index as usize] {
+            entries.push((functions.get(index), &contents[start..end]));
+        }
+    }
+    let mut rewritten = Vec::new();
+    VarUint32::from(entries.len() as u32).serialize(&mut rewritten).unwrap();
+    for (index, name) in entries {
+        VarUint32::from(index).serialize(&mut rewritten).unwrap();
+        rewritten.extend_from_slice(name);
+    }
+    Some(rewritten)
+}
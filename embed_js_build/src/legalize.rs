@@ -0,0 +1,203 @@
+//! Splitting the 64-bit integer arguments and results of imports into 32-bit halves, for
+//! JavaScript engines that cannot pass `i64` values to or from imported functions.
+//!
+//! Each import that is split keeps its index, and its callers are redirected to a new function of
+//! the import's original type that does the splitting. New items are only ever appended, so no
+//! other item is renumbered.
+
+use parity_wasm::elements::{
+    Module, Section, Type, FunctionType, ValueType, External, Internal, ImportEntry, Opcode, Opcodes,
+    InitExpr, Func, FuncBody, GlobalEntry, GlobalType, ExportEntry,
+    TypeSection, FunctionSection, GlobalSection, ExportSection, CodeSection,
+};
+
+use std::collections::HashMap;
+
+/// The exported function that a split import calls to give the high half of its `i64` result,
+/// before returning the low half.
+pub const SET_HIGH_EXPORT: &str = "__embed_js_set_i64_high";
+
+/// The id of the kind of `section`, which is also the order the kinds must appear in, or `None` for
+/// custom sections.
+fn section_order(section: &Section) -> Option<u8> {
+    match *section {
+        Section::Unparsed { id, .. } if id != 0 => Some(id),
+        Section::Type(_) => Some(1),
+        Section::Import(_) => Some(2),
+        Section::Function(_) => Some(3),
+        Section::Table(_) => Some(4),
+        Section::Memory(_) => Some(5),
+        Section::Global(_) => Some(6),
+        Section::Export(_) => Some(7),
+        Section::Start(_) => Some(8),
+        Section::Element(_) => Some(9),
+        Section::Code(_) => Some(10),
+        Section::Data(_) => Some(11),
+        _ => None,
+    }
+}
+
+/// Adds `section` to `module` in its place, unless there is already a section of its kind.
+fn ensure_section(module: &mut Module, section: Section) {
+    let order = section_order(&section).unwrap();
+    let sections = module.sections_mut();
+    if sections.iter().any(|existing| section_order(existing) == Some(order)) {
+        return;
+    }
+    let position = sections.iter()
+        .position(|existing| section_order(existing).is_some_and(|existing| existing > order))
+        .unwrap_or(sections.len());
+    sections.insert(position, section);
+}
+
+/// The index of `ty` in `types`, which it is added to if it is not there.
+fn type_index(types: &mut Vec<Type>, ty: FunctionType) -> u32 {
+    let ty = Type::Function(ty);
+    match types.iter().position(|existing| *existing == ty) {
+        Some(index) => index as u32,
+        None => {
+            types.push(ty);
+            types.len() as u32 - 1
+        }
+    }
+}
+
+/// Splits the `i64` arguments and results of the function imports in `module` for which `split`
+/// returns true. Each `i64` argument is passed as its low half followed by its high half, as
+/// `i32`s, and an `i64` result is returned as its low half after the high half has been given to
+/// the exported function named by `SET_HIGH_EXPORT`.
+pub fn split_i64<F: Fn(&ImportEntry) -> bool>(module: &mut Module, split: F) {
+    let types: Vec<FunctionType> = module.type_section()
+        .map(|section| section.types().iter().map(|ty| match *ty { Type::Function(ref ty) => ty.clone() }).collect())
+        .unwrap_or_default();
+    // the imports to split, as their positions among the imports, function indices and types
+    let mut imports = Vec::new();
+    let mut imported_functions = 0;
+    let mut imported_globals = 0;
+    if let Some(section) = module.import_section() {
+        for (i, entry) in section.entries().iter().enumerate() {
+            match *entry.external() {
+                External::Function(type_ref) => {
+                    let ty = &types[type_ref as usize];
+                    let has_i64 = ty.params().contains(&ValueType::I64) || ty.return_type() == Some(ValueType::I64);
+                    if has_i64 && split(entry) {
+                        imports.push((i, imported_functions, type_ref));
+                    }
+                    imported_functions += 1;
+                }
+                External::Global(_) => imported_globals += 1,
+                _ => {}
+            }
+        }
+    }
+    if imports.is_empty() {
+        return;
+    }
+    let function_count = imported_functions + module.function_section().map(|section| section.entries().len() as u32).unwrap_or(0);
+    let high_global = imported_globals + module.global_section().map(|section| section.entries().len() as u32).unwrap_or(0);
+    let returns_i64 = imports.iter().any(|&(_, _, type_ref)| types[type_ref as usize].return_type() == Some(ValueType::I64));
+
+    let mut all_types: Vec<Type> = types.iter().cloned().map(Type::Function).collect();
+    let mut import_types = Vec::new();
+    let mut functions = Vec::new();
+    let mut bodies = Vec::new();
+    let mut redirects = HashMap::new();
+    for &(_, function_index, type_ref) in &imports {
+        let ty = types[type_ref as usize].clone();
+        let mut params = Vec::new();
+        let mut code = Vec::new();
+        for (i, &param) in ty.params().iter().enumerate() {
+            code.push(Opcode::GetLocal(i as u32));
+            if param == ValueType::I64 {
+                params.push(ValueType::I32);
+                params.push(ValueType::I32);
+                code.extend(vec![
+                    Opcode::I32WarpI64,
+                    Opcode::GetLocal(i as u32),
+                    Opcode::I64Const(32),
+                    Opcode::I64ShrU,
+                    Opcode::I32WarpI64,
+                ]);
+            } else {
+                params.push(param);
+            }
+        }
+        code.push(Opcode::Call(function_index));
+        let return_type = if ty.return_type() == Some(ValueType::I64) {
+            code.extend(vec![
+                Opcode::I64ExtendUI32,
+                Opcode::GetGlobal(high_global),
+                Opcode::I64ExtendUI32,
+                Opcode::I64Const(32),
+                Opcode::I64Shl,
+                Opcode::I64Or,
+            ]);
+            Some(ValueType::I32)
+        } else {
+            ty.return_type()
+        };
+        code.push(Opcode::End);
+        import_types.push(type_index(&mut all_types, FunctionType::new(params, return_type)));
+        redirects.insert(function_index, function_count + functions.len() as u32);
+        functions.push(Func::new(type_ref));
+        bodies.push(FuncBody::new(Vec::new(), Opcodes::new(code)));
+    }
+    let mut globals = Vec::new();
+    let mut exports = Vec::new();
+    if returns_i64 {
+        globals.push(GlobalEntry::new(GlobalType::new(ValueType::I32, true), InitExpr::new(vec![Opcode::I32Const(0), Opcode::End])));
+        let set_high_type = type_index(&mut all_types, FunctionType::new(vec![ValueType::I32], None));
+        exports.push(ExportEntry::new(String::from(SET_HIGH_EXPORT), Internal::Function(function_count + functions.len() as u32)));
+        functions.push(Func::new(set_high_type));
+        bodies.push(FuncBody::new(Vec::new(), Opcodes::new(vec![Opcode::GetLocal(0), Opcode::SetGlobal(high_global), Opcode::End])));
+    }
+    let redirect = |index: &mut u32| if let Some(&to) = redirects.get(index) {
+        *index = to;
+    };
+
+    ensure_section(module, Section::Type(TypeSection::with_types(Vec::new())));
+    ensure_section(module, Section::Function(FunctionSection::with_entries(Vec::new())));
+    ensure_section(module, Section::Global(GlobalSection::with_entries(Vec::new())));
+    ensure_section(module, Section::Export(ExportSection::with_entries(Vec::new())));
+    ensure_section(module, Section::Code(CodeSection::with_bodies(Vec::new())));
+    for section in module.sections_mut() {
+        match *section {
+            Section::Type(ref mut section) => *section.types_mut() = all_types.clone(),
+            Section::Import(ref mut section) => {
+                for (&(i, _, _), &type_ref) in imports.iter().zip(&import_types) {
+                    let entry = &mut section.entries_mut()[i];
+                    *entry = ImportEntry::new(entry.module().to_string(), entry.field().to_string(), External::Function(type_ref));
+                }
+            }
+            Section::Function(ref mut section) => section.entries_mut().extend(functions.iter().cloned()),
+            Section::Global(ref mut section) => section.entries_mut().extend(globals.iter().cloned()),
+            Section::Export(ref mut section) => {
+                for entry in section.entries_mut() {
+                    if let Internal::Function(ref mut index) = *entry.internal_mut() {
+                        redirect(index);
+                    }
+                }
+                section.entries_mut().extend(exports.iter().cloned());
+            }
+            Section::Start(ref mut index) => redirect(index),
+            Section::Element(ref mut section) => {
+                for segment in section.entries_mut() {
+                    for member in segment.members_mut() {
+                        redirect(member);
+                    }
+                }
+            }
+            Section::Code(ref mut section) => {
+                for body in section.bodies_mut() {
+                    for opcode in body.code_mut().elements_mut() {
+                        if let Opcode::Call(ref mut index) = *opcode {
+                            redirect(index);
+                        }
+                    }
+                }
+                section.bodies_mut().extend(bodies.iter().cloned());
+            }
+            _ => {}
+        }
+    }
+}
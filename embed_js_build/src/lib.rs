@@ -5,139 +5,439 @@
 //! See the `embed_js` repository for examples of how to use these crates together.
 
 extern crate embed_js_common;
-extern crate cpp_synmap;
 extern crate cpp_syn;
+extern crate syn;
 extern crate serde_json;
 extern crate uuid;
 extern crate parity_wasm;
+extern crate base64;
 
-use cpp_synmap::SourceMap;
-use cpp_syn::visit::Visitor;
-use cpp_syn::{Mac, TokenTree, Delimited};
+mod cfg;
+mod gc;
+mod legalize;
+mod loader;
+mod resolve;
 
-use parity_wasm::elements::{Module, Section, ExportEntry, Internal};
+pub use loader::Loader;
+
+use resolve::{ Macro, Scope };
+use cfg::Attrs;
+
+use syn::visit::{ self, Visit };
+use syn::{ Item, ItemMod, ItemMacro, ImplItem, TraitItem, Local, StmtMacro, Expr, ExprLit, Arm, Lit, LitStr, Meta, MetaNameValue, Token };
+use syn::ext::IdentExt;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+
+use parity_wasm::elements::{Module, Section, ExportEntry, ImportEntry, Internal};
 
 use std::env;
 use std::path::{ PathBuf, Path };
-use std::io::{ BufWriter, BufReader, Read };
-use std::fs::File;
+use std::io::{ BufWriter, BufReader, Read, Write };
+use std::fs::{ self, File };
 use std::process::Command;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::collections::HashMap;
+use std::collections::{ HashMap, BTreeMap, BTreeSet };
+use std::mem;
 
-use embed_js_common::{ JsMac, JsMacArg };
+use embed_js_common::{ JsMac, JsMacArg, JsMacRet, PrimitiveType };
 
 struct JsVisitor<'a> {
-    source_map: &'a mut SourceMap,
-    instances: &'a mut Vec<JsMac>,
-    included_js: &'a mut String
+    included_js: &'a mut String,
+    problems: &'a mut Vec<String>,
+    /// The files and directories the output depends on.
+    watched_paths: &'a mut BTreeSet<PathBuf>,
+    /// The environment variables the output depends on.
+    watched_env_vars: &'a mut BTreeSet<String>,
+    /// The file being visited.
+    file: PathBuf,
+    /// The contents of `file`, which the byte ranges of the spans being visited index into.
+    source: String,
+    /// The directory containing the files of modules declared in the module being visited.
+    module_dir: PathBuf,
+    /// The directory that `path` attributes of modules declared in the module being visited are
+    /// relative to.
+    path_attr_dir: PathBuf,
+    /// The names imported by `use` declarations in scope.
+    scope: Scope,
+    /// The names the `embed_js` crate is known by.
+    embed_js_crates: BTreeSet<String>,
+    /// The names of the macros defined by `macro_rules!` in scope.
+    local_macros: BTreeSet<String>
 }
-impl<'a> Visitor for JsVisitor<'a> {
-    fn visit_mac(&mut self, mac: &Mac) {
-        if mac.path.segments.len() != 1 {
-            return;
-        }
-        let tts = match mac.tts[0] {
-            TokenTree::Delimited(Delimited { ref tts, .. }, _) => &**tts,
-            _ => return,
+impl<'a> JsVisitor<'a> {
+    /// Records a problem with the source at byte offset `at` in the current file, to be reported
+    /// once the whole crate has been visited.
+    fn report(&mut self, at: usize, message: String) {
+        let line = self.source.get(..at).unwrap_or("").matches('\n').count() + 1;
+        self.problems.push(format!("{}:{}: {}", self.file.display(), line, message));
+    }
+
+    /// Records that the output depends on the file or directory at `path`.
+    fn watch(&mut self, path: &Path) {
+        self.watched_paths.insert(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    }
+
+    /// Parses and visits the file at `path`, in which modules are declared relative to
+    /// `module_dir`. If the file cannot be read or parsed, it is skipped and the problem reported
+    /// as `description` at byte offset `at` in the current file.
+    fn visit_source_file(&mut self, path: &Path, module_dir: PathBuf, at: usize, description: &str) {
+        self.watch(path);
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| {
+                let source = prepare_source(source);
+                match syn::parse_file(&source) {
+                    Ok(file) => Ok((source, file)),
+                    Err(e) => {
+                        let start = e.span().start();
+                        Err(format!("line {}, column {}: {}", start.line, start.column + 1, e))
+                    }
+                }
+            });
+        let (source, file) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.report(at, format!("skipping {} {}: {}", description, path.display(), e));
+                return;
+            }
         };
-        match mac.path.segments[0].ident.as_ref() {
-            "js" => {
-                if let Ok(parsed) = embed_js_common::parse_js_mac_source_map(tts, self.source_map) {
-                    self.instances.push(parsed);
+        let path_attr_dir = path.parent().unwrap_or(path).to_path_buf();
+        let outer_file = mem::replace(&mut self.file, path.to_path_buf());
+        let outer_source = mem::replace(&mut self.source, source);
+        let outer_module_dir = mem::replace(&mut self.module_dir, module_dir);
+        let outer_path_attr_dir = mem::replace(&mut self.path_attr_dir, path_attr_dir);
+        if is_compiled(&file.attrs) {
+            self.add_uses(&file.items);
+            self.visit_file(&file);
+        }
+        self.file = outer_file;
+        self.source = outer_source;
+        self.module_dir = outer_module_dir;
+        self.path_attr_dir = outer_path_attr_dir;
+    }
+
+    /// Brings the names imported by the `use` declarations among `items` into scope, so that they
+    /// apply to the macro calls of items that come before them too.
+    fn add_uses(&mut self, items: &[Item]) {
+        for item in items {
+            if let Item::Use(ref item_use) = *item {
+                if is_compiled(&item_use.attrs) {
+                    self.scope.add_use(&item_use.tree);
                 }
             }
-            "include_js" => {
-                let js_source = if let (Some(first), Some(last)) = (tts.first(), tts.last()) {
-                    self.source_map.source_text(first.span().extend(last.span())).unwrap()
+        }
+    }
+
+    /// Visits the file of the module declared by `item`, as `mod name;`, whose attributes after
+    /// `cfg_attr` expansion are `attrs`.
+    fn visit_module_file(&mut self, item: &ItemMod, attrs: &[Meta]) {
+        let name = item.ident.unraw().to_string();
+        let at = item.mod_token.span.byte_range().start;
+        let path_attr = attrs.iter()
+            .rev()
+            .find_map(|attr| {
+                match *attr {
+                    Meta::NameValue(MetaNameValue { ref path, value: Expr::Lit(ExprLit { lit: Lit::Str(ref value), .. }), .. }) if path.is_ident("path") => Some(value.value()),
+                    _ => None,
+                }
+            });
+        let path = match path_attr {
+            Some(ref path) => self.path_attr_dir.join(path),
+            None => {
+                let adjacent = self.module_dir.join(format!("{}.rs", name));
+                let nested = self.module_dir.join(&name).join("mod.rs");
+                if adjacent.is_file() {
+                    adjacent
+                } else if nested.is_file() {
+                    nested
                 } else {
-                    ""
-                };
-                self.included_js.push_str(&js_source);
-                self.included_js.push_str("\n");
+                    // rerun once the file is created
+                    let module_dir = self.module_dir.clone();
+                    self.watch(&module_dir);
+                    self.report(at, format!("skipping `mod {}` because neither {} nor {} exists",
+                                            name, adjacent.display(), nested.display()));
+                    return;
+                }
             }
-            "include" => {
-                use cpp_syn::{ Token, Lit, LitKind };
-                let mut iter = tts.iter().peekable();
-                match iter.next() {
-                    Some(&TokenTree::Token(Token::Literal(Lit { node: LitKind::Str(ref path, _), .. }), span)) => {
-                        if iter.next().is_some() {
-                            return;
-                        }
-                        let mut path = PathBuf::from(path);
-                        if !path.is_absolute() {
-                            let root = self.source_map.filename(span).unwrap();
-                            path = root.join(path);
-                        }
-                        println!("cargo:warning=embed_js_build processing source in included file {}", path.display());
-                        let krate = self.source_map.add_crate_root(path).unwrap();
-                        self.visit_crate(&krate);
-                    }
-                    Some(&TokenTree::Token(Token::Ident(ref ident), span)) if ident.as_ref() == "concat" => {
-                        match iter.next() {
-                            Some(&TokenTree::Token(Token::Not, _)) => {}
-                            _ => return
+        };
+        // the modules declared in a `mod.rs` file or a file named by a `path` attribute are next to
+        // it, and those declared in other files are in a directory named after the file
+        let parent = path.parent().unwrap_or(&path).to_path_buf();
+        let module_dir = if path_attr.is_some() || path.file_name().is_some_and(|file_name| file_name == "mod.rs") {
+            parent
+        } else {
+            parent.join(path.file_stem().unwrap())
+        };
+        self.visit_source_file(&path, module_dir, at, &format!("the file of `mod {}`,", name));
+    }
+
+    /// Works out the path of the file named by the arguments of `mac`, a call to the macro `name`
+    /// such as `include!`, relative to the file containing the call.
+    fn include_path(&mut self, name: &str, mac: &syn::Macro) -> Result<PathBuf, (usize, String)> {
+        let at = mac.path.span().byte_range().start;
+        let source = &self.source;
+        let unsupported = |expr: &Expr| {
+            let range = expr.span().byte_range();
+            (range.start, format!("skipping `{}!` with unsupported argument `{}`; only string literals \
+                                   and `concat!` of string literals and `env!` are understood",
+                                  name, source.get(range).unwrap_or("<unknown>")))
+        };
+        let path = match mac.parse_body::<Expr>() {
+            Ok(Expr::Lit(ExprLit { lit: Lit::Str(ref path), .. })) => path.value(),
+            Ok(Expr::Macro(ref concat)) if concat.mac.path.is_ident("concat") => {
+                let parts = match concat.mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+                    Ok(parts) => parts,
+                    Err(e) => return Err((at, format!("skipping `{}!` with unparsable `concat!`: {}", name, e))),
+                };
+                let mut path = String::new();
+                for part in &parts {
+                    match *part {
+                        Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => {
+                            path.push_str(&s.value());
                         }
-                        let tts = match iter.next() {
-                            Some(&TokenTree::Delimited(Delimited { ref tts, .. }, _)) => {
-                                tts
-                            }
-                            _ => return
-                        };
-                        let mut path = String::new();
-                        let mut iter = tts.iter().peekable();
-                        while let Some(t) = iter.next() {
-                            match *t {
-                                TokenTree::Token(Token::Literal(Lit { node: LitKind::Str(ref s, _), .. }), _) => {
-                                    path.push_str(s);
+                        Expr::Macro(ref env) if env.mac.path.is_ident("env") => {
+                            let env_at = env.mac.path.span().byte_range().start;
+                            let var = match env.mac.parse_body::<LitStr>() {
+                                Ok(var) => var.value(),
+                                Err(_) => {
+                                    return Err((env_at, format!("skipping `{}!` with unsupported `env!` \
+                                                                 call; only a single string literal argument \
+                                                                 is understood", name)))
                                 }
-                                TokenTree::Token(Token::Comma, _) => {}
-                                TokenTree::Token(Token::Ident(ref ident), _) if ident.as_ref() == "env" => {
-                                    match iter.next() {
-                                        Some(&TokenTree::Token(Token::Not, _)) => {}
-                                        _ => return
-                                    }
-                                    let tts = match iter.next() {
-                                        Some(&TokenTree::Delimited(Delimited { ref tts, .. }, _)) => {
-                                            tts
-                                        }
-                                        _ => return
-                                    };
-                                    if let Some(&TokenTree::Token(Token::Literal(Lit { node: LitKind::Str(ref s, _), .. }), _)) = tts.first() {
-                                        if tts.len() != 1 {
-                                            return
-                                        }
-                                        if let Ok(v) = std::env::var(s) {
-                                            path.push_str(&v);
-                                        } else {
-                                            return
-                                        }
-                                    } else {
-                                        return
-                                    }
+                            };
+                            self.watched_env_vars.insert(var.clone());
+                            match env::var(&var) {
+                                Ok(v) => path.push_str(&v),
+                                Err(_) => {
+                                    return Err((env_at, format!("skipping `{}!` because environment \
+                                                                 variable `{}` is not set", name, var)))
                                 }
-                                _ => return
                             }
                         }
-                        let mut path = PathBuf::from(path);
-                        if !path.is_absolute() {
-                            let root = self.source_map.filename(span).unwrap();
-                            path = root.join(path);
+                        ref other => return Err(unsupported(other))
+                    }
+                }
+                path
+            }
+            Ok(ref other) => return Err(unsupported(other)),
+            Err(e) => return Err((at, format!("skipping `{}!` with unsupported arguments: {}", name, e))),
+        };
+        let mut path = PathBuf::from(path);
+        if !path.is_absolute() {
+            path = self.file.parent().unwrap_or(&self.file).join(path);
+        }
+        Ok(path)
+    }
+}
+/// Whether something with the attributes `attrs` is compiled in the configuration the build script
+/// is run for.
+fn is_compiled(attrs: &[syn::Attribute]) -> bool {
+    cfg::is_enabled(&cfg::expand_attrs(attrs))
+}
+
+/// Removes any byte order mark from `source` and blanks out any shebang line, which
+/// `syn::parse_file` would otherwise strip, so that the byte ranges of the spans it produces index
+/// into the result.
+fn prepare_source(source: String) -> String {
+    let mut source = match source.strip_prefix('\u{feff}') {
+        Some(stripped) => stripped.to_string(),
+        None => source,
+    };
+    // `#![...]` is an inner attribute rather than a shebang
+    if source.starts_with("#!") && !source[2..].trim_start().starts_with('[') {
+        let end = source.find('\n').unwrap_or(source.len());
+        source.replace_range(..end, &" ".repeat(end));
+    }
+    source
+}
+
+impl<'a, 'ast> Visit<'ast> for JsVisitor<'a> {
+    fn visit_item(&mut self, item: &'ast Item) {
+        let attrs = cfg::expand_attrs(item.attrs());
+        if !cfg::is_enabled(&attrs) {
+            return;
+        }
+        match *item {
+            Item::Mod(ref module) => {
+                // each module has its own imports, and macros defined in it stay there unless it is
+                // marked `#[macro_use]`
+                let outer_scope = mem::take(&mut self.scope);
+                let outer_local_macros = self.local_macros.clone();
+                match module.content {
+                    None => self.visit_module_file(module, &attrs),
+                    Some((_, ref items)) => {
+                        let module_dir = self.module_dir.join(module.ident.unraw().to_string());
+                        let outer_module_dir = mem::replace(&mut self.module_dir, module_dir.clone());
+                        let outer_path_attr_dir = mem::replace(&mut self.path_attr_dir, module_dir);
+                        self.add_uses(items);
+                        visit::visit_item(self, item);
+                        self.module_dir = outer_module_dir;
+                        self.path_attr_dir = outer_path_attr_dir;
+                    }
+                }
+                self.scope = outer_scope;
+                if !attrs.iter().any(|attr| attr.path().is_ident("macro_use")) {
+                    self.local_macros = outer_local_macros;
+                }
+            }
+            Item::Use(ref item_use) => self.scope.add_use(&item_use.tree),
+            Item::ExternCrate(ref extern_crate) => {
+                if extern_crate.ident == "embed_js" {
+                    let name = extern_crate.rename.as_ref().map_or(&extern_crate.ident, |(_, rename)| rename);
+                    self.embed_js_crates.insert(name.to_string());
+                }
+            }
+            Item::Macro(ItemMacro { ident: Some(ref name), ref mac, .. }) if mac.path.is_ident("macro_rules") => {
+                self.local_macros.insert(name.unraw().to_string());
+            }
+            // macro calls such as `include!` add to the current module
+            Item::Macro(_) => visit::visit_item(self, item),
+            _ => {
+                // imports and macros in blocks only apply within them
+                let outer_scope = self.scope.clone();
+                let outer_local_macros = self.local_macros.clone();
+                visit::visit_item(self, item);
+                self.scope = outer_scope;
+                self.local_macros = outer_local_macros;
+            }
+        }
+    }
+
+    fn visit_impl_item(&mut self, impl_item: &'ast ImplItem) {
+        if is_compiled(impl_item.attrs()) {
+            visit::visit_impl_item(self, impl_item);
+        }
+    }
+
+    fn visit_trait_item(&mut self, trait_item: &'ast TraitItem) {
+        if is_compiled(trait_item.attrs()) {
+            visit::visit_trait_item(self, trait_item);
+        }
+    }
+
+    fn visit_stmt_macro(&mut self, stmt: &'ast StmtMacro) {
+        if is_compiled(&stmt.attrs) {
+            visit::visit_stmt_macro(self, stmt);
+        }
+    }
+
+    fn visit_local(&mut self, local: &'ast Local) {
+        if is_compiled(&local.attrs) {
+            visit::visit_local(self, local);
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        if is_compiled(expr.attrs()) {
+            visit::visit_expr(self, expr);
+        }
+    }
+
+    fn visit_arm(&mut self, arm: &'ast Arm) {
+        if is_compiled(&arm.attrs) {
+            visit::visit_arm(self, arm);
+        }
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        let found = match self.scope.resolve(&mac.path, &self.embed_js_crates, &self.local_macros) {
+            Some(found) => found,
+            None => return,
+        };
+        let at = mac.path.span().byte_range().start;
+        match found {
+            Macro::Js => {
+                // embed_js_common parses the arguments from `cpp_syn` tokens of the text between the
+                // delimiters, whose spans are offsets from `lo`
+                let lo = mac.delimiter.span().open().byte_range().end;
+                let hi = mac.delimiter.span().close().byte_range().start;
+                let source = &self.source[lo..hi];
+                let tts = match cpp_syn::parse_token_trees(source) {
+                    Ok(tts) => tts,
+                    Err(e) => {
+                        self.report(at, format!("skipping unparsable `js!` call: {}", e));
+                        return;
+                    }
+                };
+                // a body read from a file is found relative to the current file, like `include_str!`
+                let dir = self.file.parent().unwrap_or(&self.file).to_path_buf();
+                let mut body_file = None;
+                let parsed = embed_js_common::parse_js_mac_string_source(&tts, source, |path, _| {
+                    let path = dir.join(path);
+                    let body = fs::read_to_string(&path);
+                    body_file = Some(path);
+                    body
+                });
+                if let Some(path) = body_file {
+                    self.watch(&path);
+                }
+                // the calls themselves are recorded in the binary by the `js` macro, and are only
+                // parsed here to report the ones that cannot be
+                if let Err(e) = parsed {
+                    self.report(lo + e.span.lo, format!("skipping unparsable `js!` call: {}", e));
+                }
+            }
+            Macro::IncludeJs => {
+                let mut tokens = mac.tokens.clone().into_iter();
+                if let Some(first) = tokens.next() {
+                    let last = tokens.last().unwrap_or_else(|| first.clone());
+                    let js_source = &self.source[first.span().byte_range().start..last.span().byte_range().end];
+                    self.included_js.push_str(js_source);
+                }
+                self.included_js.push('\n');
+            }
+            Macro::IncludeJsFile => {
+                match self.include_path("include_js_file", mac) {
+                    Ok(path) => {
+                        self.watch(&path);
+                        match fs::read_to_string(&path) {
+                            Ok(js_source) => {
+                                self.included_js.push_str(&js_source);
+                                self.included_js.push('\n');
+                            }
+                            Err(e) => self.report(at, format!("skipping `include_js_file!` of {}: {}", path.display(), e)),
                         }
+                    }
+                    Err((at, message)) => self.report(at, message),
+                }
+            }
+            Macro::Include => {
+                match self.include_path("include", mac) {
+                    Ok(path) => {
                         println!("cargo:warning=embed_js_build processing source in included file {}", path.display());
-                        let krate = self.source_map.add_crate_root(path).unwrap();
-                        self.visit_crate(&krate);
+                        // the included items belong to the current module
+                        let module_dir = self.module_dir.clone();
+                        self.visit_source_file(&path, module_dir, at, "`include!` of");
                     }
-                    _ => return
+                    Err((at, message)) => self.report(at, message),
                 }
             }
-            _ => {}
         }
     }
 }
 
-/// Call this once from a build script for a crate that uses `embed_js` directly.
+/// Call this once from a build script for a crate that uses the `include_js` or `include_js_file`
+/// macros. Crates that only use the `js` macro do not need a build script.
+///
+/// Code disabled by `cfg` attributes is skipped, with the predicates evaluated against the
+/// configuration and features cargo describes to the build script through `CARGO_CFG_*` and
+/// `CARGO_FEATURE_*` environment variables. `cfg_attr` attributes are expanded in the same way, so
+/// module files chosen by `#[cfg_attr(..., path = "...")]` are found.
+///
+/// The macros are recognised however they are named: through `#[macro_use]`, by paths such as
+/// `embed_js::js!` (including through `extern crate embed_js as ...` renames), or as imported by
+/// `use` declarations such as `use embed_js::js as javascript;`. Macros defined in the crate with
+/// `macro_rules!` that merely share a name with one of them are ignored.
+///
+/// Every file that is read, including module files, `include!`d files and files named by
+/// `include_js_file`, is registered with `cargo:rerun-if-changed`, and every environment variable
+/// read by `env!` in the path of an `include!` with `cargo:rerun-if-env-changed`, so the build
+/// script reruns exactly when its output may change.
+///
+/// Source files are parsed as current stable Rust. Any source file that cannot be parsed, any `js`
+/// macro call that cannot be parsed, and any module, `include!` or `include_js_file!` whose file
+/// cannot be located or processed, is skipped with a `cargo:warning` giving its file and line. Use
+/// `preprocess_crate_strict` to fail the build instead.
 ///
 /// Parameters:
 ///
@@ -154,39 +454,255 @@ impl<'a> Visitor for JsVisitor<'a> {
 /// }
 /// ```
 pub fn preprocess_crate(lib_root: &Path) {
-    let mut source_map = SourceMap::new();
+    preprocess(lib_root, false)
+}
+
+/// Like `preprocess_crate`, but panics, failing the build, if any source file, `js` macro call,
+/// module, `include!` or `include_js_file!` had to be skipped.
+pub fn preprocess_crate_strict(lib_root: &Path) {
+    preprocess(lib_root, true)
+}
+
+fn preprocess(lib_root: &Path, strict: bool) {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let mut instances = Vec::new();
     let mut included_js = String::new();
-    let krate = source_map.add_crate_root(lib_root).unwrap();
+    let mut problems = Vec::new();
+    let mut watched_paths = BTreeSet::new();
+    let mut watched_env_vars = BTreeSet::new();
+    let root_dir = lib_root.parent().unwrap_or(lib_root).to_path_buf();
     JsVisitor {
-        source_map: &mut source_map,
-        instances: &mut instances,
-        included_js: &mut included_js
-    }.visit_crate(&krate);
+        included_js: &mut included_js,
+        problems: &mut problems,
+        watched_paths: &mut watched_paths,
+        watched_env_vars: &mut watched_env_vars,
+        file: lib_root.to_path_buf(),
+        source: String::new(),
+        module_dir: root_dir.clone(),
+        path_attr_dir: root_dir.clone(),
+        scope: Scope::default(),
+        embed_js_crates: BTreeSet::from(["embed_js".to_string()]),
+        local_macros: BTreeSet::new()
+    }.visit_source_file(lib_root, root_dir, 0, "the crate root");
+
+    // rerun exactly when something that was read changes
+    for path in &watched_paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    for var in &watched_env_vars {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+
+    if strict && !problems.is_empty() {
+        panic!("embed_js_build could not process the following:\n{}", problems.join("\n"));
+    }
+    for problem in &problems {
+        println!("cargo:warning=embed_js_build: {}", problem);
+    }
 
     let js_path = out_dir.join("embed_js_data.json");
-    serde_json::to_writer(BufWriter::new(File::create(&js_path).unwrap()), &(instances, included_js)).unwrap();
+    serde_json::to_writer(BufWriter::new(File::create(&js_path).unwrap()), &included_js).unwrap();
     let preamble_path = out_dir.join("embed_js_preamble.rs");
     File::create(preamble_path).unwrap();
 }
 
+/// Assigns the import names for `js_macs` in the binary written by `postprocess_crate`.
+///
+/// Each call is named by `embed_js_common::import_name` where that name is unique. Calls whose
+/// names collide are all given that name followed by `_` and their position among the colliding
+/// calls in order of their canonical serializations, so that the result only depends on the calls.
+fn assign_import_names(js_macs: &BTreeSet<JsMac>) -> HashMap<JsMac, String> {
+    let mut by_name: BTreeMap<String, Vec<(String, &JsMac)>> = BTreeMap::new();
+    for js_mac in js_macs {
+        by_name.entry(embed_js_common::import_name(js_mac))
+            .or_default()
+            .push((serde_json::to_string(js_mac).unwrap(), js_mac));
+    }
+    let mut names = HashMap::new();
+    for (name, mut colliding) in by_name {
+        if colliding.len() == 1 {
+            names.insert(colliding[0].1.clone(), name);
+        } else {
+            colliding.sort();
+            for (i, (_, js_mac)) in colliding.into_iter().enumerate() {
+                names.insert(js_mac.clone(), format!("{}_{}", name, i));
+            }
+        }
+    }
+    names
+}
+
+/// Removes the custom sections in which `embed_js_derive` records `js` macro calls from `module`,
+/// returning the calls they contained.
+fn take_recorded_js_macs(module: &mut Module) -> Vec<JsMac> {
+    let mut payload = Vec::new();
+    module.sections_mut().retain(|section| {
+        match *section {
+            Section::Custom(ref custom) if custom.name() == embed_js_common::JS_MACS_SECTION => {
+                payload.extend_from_slice(custom.payload());
+                false
+            }
+            _ => true
+        }
+    });
+    payload.split(|&byte| byte == b'\n')
+        .filter(|json| !json.is_empty())
+        .map(|json| serde_json::from_slice(json).unwrap())
+        .collect()
+}
+
+/// The name under which the JavaScript sees the argument named `name` in Rust, which drops the `r#`
+/// of a raw identifier such as `r#type`.
+fn js_name(name: String) -> String {
+    match name.strip_prefix("r#") {
+        Some(raw) => raw.to_string(),
+        None => name,
+    }
+}
+
+/// Support code for the `js` macro's generated imports, put at the start of `included`.
+///
+/// `embed_js_heap` is the table of JavaScript values referred to by `embed_js::JsRef`, indexed
+/// from 1 so that a slot is never 0. Freed slots are reused.
+///
+/// `embed_js_closure` makes the JavaScript function for an `embed_js::Closure` from the function
+/// table indices of its call and drop functions and the pointer to its data. A closure being
+/// dropped while it runs is only dropped once the call returns.
+const JS_RUNTIME: &str = "\
+var embed_js_heap = (function() {
+    var values = [undefined];
+    var free = [];
+    return {
+        insert: function(value) {
+            var id = free.length > 0 ? free.pop() : values.length;
+            values[id] = value;
+            return id;
+        },
+        get: function(id) {
+            return values[id];
+        },
+        clone: function(id) {
+            return this.insert(values[id]);
+        },
+        drop: function(id) {
+            values[id] = undefined;
+            free.push(id);
+        }
+    };
+})();
+var embed_js_closure = function(call, data, drop, once) {
+    call = wasm_table.get(call);
+    drop = wasm_table.get(drop);
+    var running = false;
+    var dropped = false;
+    var drop_requested = false;
+    var f = function() {
+        if (dropped) {
+            throw new Error(\"embed_js: closure called after being dropped\");
+        }
+        if (running) {
+            throw new Error(\"embed_js: closure called while already running\");
+        }
+        running = true;
+        try {
+            return call.apply(null, [data].concat(Array.prototype.slice.call(arguments)));
+        } finally {
+            running = false;
+            if (once || drop_requested) {
+                f.drop();
+            }
+        }
+    };
+    f.drop = function() {
+        if (running) {
+            drop_requested = true;
+        } else if (!dropped) {
+            dropped = true;
+            drop(data);
+        }
+    };
+    return f;
+};
+";
+
+/// How `postprocess_crate_with` passes `i64` arguments and return values of `js` macro calls
+/// between wasm and JavaScript, as set by `PostProcessOptions::i64_mode`. Either way, the JavaScript code sees them as `BigInt`s, and
+/// can return a `BigInt` or a number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum I64Mode {
+    /// Each `i64` is passed as two 32-bit halves, which the generated imports turn into a `BigInt`
+    /// and back. The binary has no `i64` in the signatures of its imports, so this works in every
+    /// engine that supports `BigInt`.
+    Split,
+    /// Each `i64` is passed as a `BigInt` by the engine itself, which needs support for the
+    /// WebAssembly JS-BigInt integration.
+    BigInt,
+}
+
+/// Options for `postprocess_crate_with`. The defaults are those used by `postprocess_crate`.
+#[derive(Clone, Debug)]
+pub struct PostProcessOptions {
+    i64_mode: I64Mode,
+    external_gc: bool,
+}
+
+impl Default for PostProcessOptions {
+    fn default() -> PostProcessOptions {
+        PostProcessOptions::new()
+    }
+}
+
+impl PostProcessOptions {
+    /// Creates the default options, which split `i64` values and remove unused code in-process
+    /// only.
+    pub fn new() -> PostProcessOptions {
+        PostProcessOptions {
+            i64_mode: I64Mode::Split,
+            external_gc: false,
+        }
+    }
+
+    /// Sets how `i64` arguments and return values of `js` macro calls are passed.
+    pub fn i64_mode(mut self, i64_mode: I64Mode) -> PostProcessOptions {
+        self.i64_mode = i64_mode;
+        self
+    }
+
+    /// Sets whether to also run the external `wasm-gc` tool on the binary, which must then be
+    /// installed, before unused code is removed in-process. The binary built by cargo is still
+    /// left untouched.
+    pub fn external_gc(mut self, external_gc: bool) -> PostProcessOptions {
+        self.external_gc = external_gc;
+        self
+    }
+}
+
 /// Generated from `postprocess_crate`.
 pub struct PostProcessData {
-    /// The path to the generated wasm binary.
+    /// The path to the generated wasm binary, in an `embed_js` directory next to the binary built by
+    /// cargo.
     pub wasm_path: PathBuf,
     /// The contents of the wasm binary, provided for convenience.
     pub wasm: Vec<u8>,
     /// The javascript that should be put as the value of the `env` field in the `importObject`
     /// passed to `WebAssembly.instantiate`.
     pub imports: String,
-    /// All javascript specified by the `include_js` macro in linked crates. This should be run
-    /// before the WebAssembly module is loaded.
+    /// All javascript specified by the `include_js` and `include_js_file` macros in linked crates,
+    /// preceded by support code for the generated imports. This should be run before the WebAssembly module is loaded.
     pub included: String
 }
 /// Call this once **after** a wasm-unknown-unknown build has completed (i.e. from a post-build
 /// script) in order to generate the javascript imports that should accompany the wasm binary.
 ///
+/// The `js` macro calls that were compiled into the binary are recorded in a custom section of
+/// it. This function leaves the binary built by cargo untouched, and writes a new binary to an
+/// `embed_js` directory next to it with that section removed, with the imports for the calls given
+/// short names, with unused functions, globals, types and imports removed and with the function
+/// table exported as `__table`. Calls whose short names would collide are told apart with a
+/// suffix, so a build never fails because of a collision. The `i64` arguments and return values of
+/// calls are split into 32-bit halves, as described for `I64Mode::Split`. The output only depends
+/// on the input binary, and no external tools are needed. `postprocess_crate_with` can instead pass
+/// `i64` values natively, with `PostProcessOptions::i64_mode`, or also run `wasm-gc`.
+///
 /// See the `embed_js` repository for example projects using this function.
 ///
 /// Parameters:
@@ -196,57 +712,34 @@ pub struct PostProcessData {
 /// * `debug` Whether to look for the debug or release binary to process. Until wasm32-unkown-unknown
 ///   supports debug builds, this should always be set to `false`.
 ///
+/// The returned data can be turned into a page or script that loads the binary with `Loader`.
+///
 /// Example post-build script, taken from the "simple" example in the `embed_js` repository:
 ///
 /// ```ignore
-/// extern crate base64;
 /// extern crate embed_js_build;
 ///
-/// use std::fs::File;
-/// use std::io::Write;
+/// use embed_js_build::Loader;
 ///
 /// fn main() {
 ///     let pp_data = embed_js_build::postprocess_crate("simple", false).unwrap();
-///     let in_base_64 = base64::encode(&pp_data.wasm);
-///     let html_path = pp_data.wasm_path.with_extension("html");
-///     let mut html_file = File::create(&html_path).unwrap();
-///     write!(html_file, r#"<!DOCTYPE html>
-/// <html lang="en">
-/// <head>
-/// <meta charset="utf-8">
-/// <title> wasm test </title>
-/// <script>
-/// function _base64ToArrayBuffer(base64) {{
-///     var binary_string =  window.atob(base64);
-///     var len = binary_string.length;
-///     var bytes = new Uint8Array( len );
-///     for (var i = 0; i < len; ++i) {{
-///         bytes[i] = binary_string.charCodeAt(i);
-///     }}
-///     return bytes.buffer;
-/// }}
-/// var bytes = _base64ToArrayBuffer(
-/// "{}"
-/// );
-/// WebAssembly.instantiate(bytes, {{ env: {{
-/// {}
-/// }}}}).then(results => {{
-///     window.exports = results.instance.exports;
-///     console.log(results.instance.exports.add_two(2));
-/// }});
-/// </script>
-/// </head>
-/// </html>
-/// "#,
-///            in_base_64,
-///            pp_data.imports
-///     ).unwrap();
+///     Loader::new()
+///         .entry_point("entry_point")
+///         .write_html(&pp_data)
+///         .unwrap();
 /// }
+/// ```
 pub fn postprocess_crate(lib_name: &str, debug: bool) -> std::io::Result<PostProcessData> {
-    let metadata_json = Command::new("cargo").args(&["metadata", "--format-version", "1"]).output().unwrap().stdout;
+    postprocess_crate_with(lib_name, debug, &PostProcessOptions::new())
+}
+
+/// The same as `postprocess_crate`, but with the given `options`.
+pub fn postprocess_crate_with(lib_name: &str, debug: bool, options: &PostProcessOptions) -> std::io::Result<PostProcessData> {
+    let i64_mode = options.i64_mode;
+    let metadata_json = Command::new("cargo").args(["metadata", "--format-version", "1"]).output().unwrap().stdout;
     let metadata_json: serde_json::Value = serde_json::from_slice(&metadata_json).unwrap();
     let target_directory = Path::new(metadata_json.as_object().unwrap().get("target_directory").unwrap().as_str().unwrap());
-    let bin_prefix = target_directory.join(&format!("wasm32-unknown-unknown/{}/{}", if debug { "debug" } else { "release" }, lib_name));
+    let bin_prefix = target_directory.join(format!("wasm32-unknown-unknown/{}/{}", if debug { "debug" } else { "release" }, lib_name));
 
     // collect json data from all dependency crates
     let d_path = bin_prefix.with_extension("d");
@@ -264,43 +757,72 @@ pub fn postprocess_crate(lib_name: &str, debug: bool) -> std::io::Result<PostPro
         }
     }
     d_pieces.remove(0); // remove lib path
-    let mut js_macs: HashMap<String, JsMac> = HashMap::new();
-    let mut included_js = String::new();
+    let mut included_js = String::from(JS_RUNTIME);
     for path in d_pieces {
         if path.ends_with("out/embed_js_preamble.rs") || path.ends_with("out\\embed_js_preamble.rs") {
             let data_path = PathBuf::from(path).with_file_name("embed_js_data.json");
-            let (mut crate_js_macs, crate_included_js): (Vec<JsMac>, String) = serde_json::from_reader(BufReader::new(File::open(data_path)?)).unwrap();
+            let crate_included_js: String = serde_json::from_reader(BufReader::new(File::open(data_path)?)).unwrap();
             included_js.push_str(&crate_included_js);
-            for js_mac in crate_js_macs.drain(..) {
-                let mut hasher = DefaultHasher::new();
-                js_mac.hash(&mut hasher);
-                let mac_hash = hasher.finish();
-                let key = format!("__embed_js__{:x}", mac_hash);
-                if let Some(existing) = js_macs.get(&key) {
-                    if *existing != js_mac {
-                        panic!("A hash collision has occurred in the embed_js build process. Please raise a bug! Meanwhile, try making small changes to your embedded js to remove the collision.")
-                    }
+        }
+    }
+
+    let input_path = bin_prefix.with_extension("wasm");
+    let output_dir = input_path.with_file_name("embed_js");
+    fs::create_dir_all(&output_dir)?;
+    let wasm_path = output_dir.join(input_path.file_name().unwrap());
+    let mut module: Module = if options.external_gc {
+        // written to the output path, which is overwritten with the final binary below
+        match Command::new("wasm-gc").arg(&input_path).arg(&wasm_path).output() {
+            Ok(output) => {
+                if !output.status.success() {
+                    panic!("wasm-gc encountered an error.\n\nstatus: {}\n\nstdout:\n\n{}\n\nstderr:\n\n{}",
+                           output.status,
+                           String::from_utf8(output.stdout).unwrap_or_else(|_| String::from("<error decoding stdout>")),
+                           String::from_utf8(output.stderr).unwrap_or_else(|_| String::from("<error decoding stderr>")))
                 }
-                js_macs.insert(key, js_mac);
             }
+            Err(e) => panic!("Error attempting to run wasm-gc, which was requested with `PostProcessOptions::external_gc`. Have you got it installed? Error message: {}", e)
         }
-    }
+        parity_wasm::deserialize_file(&wasm_path).unwrap()
+    } else {
+        parity_wasm::deserialize_file(&input_path).unwrap()
+    };
+
+    // collect the js macro calls recorded in the binary itself, removing them from the output
+    let recorded: HashMap<String, JsMac> = take_recorded_js_macs(&mut module)
+        .into_iter()
+        .map(|js_mac| (embed_js_common::link_name(&js_mac), js_mac))
+        .collect();
+
+    // remove unused code, including the imports for js macro calls that are never made
+    gc::prune(&mut module);
 
-    let wasm_path = bin_prefix.with_extension("wasm");
-    match Command::new("wasm-gc").args(&[&wasm_path, &wasm_path]).output() {
-        Ok(output) => {
-            if !output.status.success() {
-                panic!("wasm-gc encountered an error.\n\nstatus: {}\n\nstdout:\n\n{}\n\nstderr:\n\n{}",
-                       output.status,
-                       String::from_utf8(output.stdout).unwrap_or_else(|_| String::from("<error decoding stdout>")),
-                       String::from_utf8(output.stderr).unwrap_or_else(|_| String::from("<error decoding stderr>")))
+    // find the js macro call of each import, and give the imports short names
+    let mut js_macs = BTreeMap::new();
+    for section in module.sections_mut() {
+        if let Section::Import(ref mut import_section) = *section {
+            let mut calls = Vec::new();
+            for (i, entry) in import_section.entries().iter().enumerate() {
+                if entry.module() == "env" {
+                    if let Some(js_mac) = recorded.get(entry.field()) {
+                        calls.push((i, js_mac.clone()));
+                    }
+                }
+            }
+            let names = assign_import_names(&calls.iter().map(|(_, js_mac)| js_mac.clone()).collect());
+            let entries = import_section.entries_mut();
+            for (i, js_mac) in calls {
+                let name = names[&js_mac].clone();
+                entries[i] = ImportEntry::new(String::from("env"), name.clone(), entries[i].external().clone());
+                js_macs.insert(name, js_mac);
             }
         }
-        Err(e) => panic!("Error attempting to run wasm-gc. Have you got it installed? Error message: {}", e)
     }
-    let mut wasm = Vec::new();
-    BufReader::new(File::open(&wasm_path)?).read_to_end(&mut wasm)?;
-    let mut module: Module = parity_wasm::deserialize_buffer(wasm.clone()).unwrap();
+
+    if i64_mode == I64Mode::Split {
+        legalize::split_i64(&mut module, |entry| entry.module() == "env" && js_macs.contains_key(entry.field()));
+    }
+
     // modify the module to export the function table
     let has_table_export = module.export_section()
         .map(|exports| exports.entries()
@@ -310,18 +832,14 @@ pub fn postprocess_crate(lib_name: &str, debug: bool) -> std::io::Result<PostPro
     if !has_table_export && module.table_section().is_some() {
         let sections = module.sections_mut();
         for section in sections {
-            match *section {
-                Section::Export(ref mut exports) => {
-                    exports.entries_mut().push(ExportEntry::new("__table".to_string(), Internal::Table(0)));
-                    break;
-                }
-                _ => {}
+            if let Section::Export(ref mut exports) = *section {
+                exports.entries_mut().push(ExportEntry::new("__table".to_string(), Internal::Table(0)));
+                break;
             }
         }
     }
-    parity_wasm::serialize_to_file(&wasm_path, module.clone()).unwrap();
-    wasm.clear();
-    BufReader::new(File::open(&wasm_path)?).read_to_end(&mut wasm)?;
+    let wasm = parity_wasm::serialize(module.clone()).unwrap();
+    File::create(&wasm_path)?.write_all(&wasm)?;
     let mut imports = String::new();
     if let Some(import_section) = module.import_section() {
         for entry in import_section.entries() {
@@ -330,23 +848,116 @@ pub fn postprocess_crate(lib_name: &str, debug: bool) -> std::io::Result<PostPro
                     if !imports.is_empty() {
                         imports.push_str(",\n");
                     }
-                    imports.push_str(&format!("{}:function(", entry.field()));
-                    let mut start = true;
+                    let mut params = Vec::new();
+                    let mut prologue = String::new();
                     for arg in mac.args {
-                        if !start {
-                            imports.push_str(", ");
-                        } else {
-                            start = false;
-                        }
                         match arg {
-                            JsMacArg::Ref(_, _, name) |
-                            JsMacArg::Primitive(_, name, _) => imports.push_str(&name)
+                            JsMacArg::Ref(_, _, name) => params.push(js_name(name)),
+                            JsMacArg::Primitive(_, name, ty) => {
+                                let name = js_name(name);
+                                // converted to the natural JavaScript value of the type
+                                match ty {
+                                    PrimitiveType::Bool => prologue.push_str(&format!("{0}={0}!==0;", name)),
+                                    PrimitiveType::U32 | PrimitiveType::Usize | PrimitiveType::Pointer(..) => {
+                                        prologue.push_str(&format!("{0}={0}>>>0;", name));
+                                    }
+                                    PrimitiveType::Char => prologue.push_str(&format!("{0}=String.fromCodePoint({0});", name)),
+                                    PrimitiveType::I64 if i64_mode == I64Mode::Split => {
+                                        // passed as its low half, followed by its high half
+                                        let high = format!("{}$high", name);
+                                        prologue.push_str(&format!("{0}=BigInt({1})<<BigInt(32)|BigInt({0}>>>0);", name, high));
+                                        params.push(name);
+                                        params.push(high);
+                                        continue;
+                                    }
+                                    _ => {}
+                                }
+                                params.push(name);
+                            }
+                            JsMacArg::Str(name) => {
+                                let name = js_name(name);
+                                // passed as a pointer and a length, decoded into a string of the same name
+                                let len = format!("{}$len", name);
+                                prologue.push_str(&format!("{0}=new TextDecoder(\"utf-8\").decode(new Uint8Array(wasm_mem.buffer,{0},{1}));", name, len));
+                                params.push(name);
+                                params.push(len);
+                            }
+                            JsMacArg::Slice(name, _, ty) => {
+                                let name = js_name(name);
+                                // passed as a pointer and a length, viewed as a typed array of the same name
+                                let array = match ty {
+                                    PrimitiveType::I8 => "Int8Array",
+                                    PrimitiveType::U8 => "Uint8Array",
+                                    PrimitiveType::I16 => "Int16Array",
+                                    PrimitiveType::U16 => "Uint16Array",
+                                    PrimitiveType::U32 | PrimitiveType::Usize => "Uint32Array",
+                                    PrimitiveType::I64 => "BigInt64Array",
+                                    PrimitiveType::F32 => "Float32Array",
+                                    PrimitiveType::F64 => "Float64Array",
+                                    _ => "Int32Array",
+                                };
+                                let len = format!("{}$len", name);
+                                prologue.push_str(&format!("{0}=new {1}(wasm_mem.buffer,{0}>>>0,{2}>>>0);", name, array, len));
+                                params.push(name);
+                                params.push(len);
+                            }
+                            JsMacArg::JsRef(name) => {
+                                let name = js_name(name);
+                                // passed as an index into embed_js_heap
+                                prologue.push_str(&format!("{0}=embed_js_heap.get({0});", name));
+                                params.push(name);
+                            }
                         }
                     }
-                    if let Some(body) = mac.body {
-                        imports.push_str(&format!("){{{}}}", body));
-                    } else {
-                        imports.push_str("){}\n");
+                    match mac.ret {
+                        Some(JsMacRet::String) | Some(JsMacRet::Bytes) => {
+                            // copied into memory from __embed_js_alloc, with the length written through
+                            // an extra pointer argument
+                            params.push(String::from("$out"));
+                            let encode = if mac.ret == Some(JsMacRet::String) {
+                                "new TextEncoder(\"utf-8\").encode($ret)"
+                            } else {
+                                "new Uint8Array($ret)"
+                            };
+                            imports.push_str(&format!("{}:function({}){{{}", entry.field(), params.join(", "), prologue));
+                            imports.push_str(&format!("var $ret=(function(){{{}\n}})();", mac.body.unwrap_or_default()));
+                            imports.push_str(&format!("var $bytes={};\
+                                                       var $ptr=wasm_exports.__embed_js_alloc($bytes.length);\
+                                                       new Uint8Array(wasm_mem.buffer,$ptr,$bytes.length).set($bytes);\
+                                                       new Uint32Array(wasm_mem.buffer,$out,1)[0]=$bytes.length;\
+                                                       return $ptr;}}", encode));
+                        }
+                        Some(JsMacRet::Primitive(PrimitiveType::Bool))
+                        | Some(JsMacRet::Primitive(PrimitiveType::Char))
+                        | Some(JsMacRet::Primitive(PrimitiveType::I64)) => {
+                            // converted from the natural JavaScript value of the type
+                            let ret = match mac.ret {
+                                Some(JsMacRet::Primitive(PrimitiveType::Bool)) => String::from("$ret?1:0"),
+                                Some(JsMacRet::Primitive(PrimitiveType::Char)) => String::from("String($ret).codePointAt(0)"),
+                                // the high half is handed over first, and the low half returned
+                                _ if i64_mode == I64Mode::Split => {
+                                    format!("($ret=BigInt.asIntN(64,BigInt($ret)),\
+                                             wasm_exports.{}(Number($ret>>BigInt(32))),\
+                                             Number(BigInt.asIntN(32,$ret)))", legalize::SET_HIGH_EXPORT)
+                                }
+                                _ => String::from("BigInt($ret)"),
+                            };
+                            imports.push_str(&format!("{}:function({}){{{}", entry.field(), params.join(", "), prologue));
+                            imports.push_str(&format!("var $ret=(function(){{{}\n}})();return {};}}", mac.body.unwrap_or_default(), ret));
+                        }
+                        Some(JsMacRet::JsRef) => {
+                            imports.push_str(&format!("{}:function({}){{{}", entry.field(), params.join(", "), prologue));
+                            imports.push_str(&format!("return embed_js_heap.insert((function(){{{}\n}})());}}", mac.body.unwrap_or_default()));
+                        }
+                        _ => {
+                            imports.push_str(&format!("{}:function({}){{{}", entry.field(), params.join(", "), prologue));
+                            if let Some(body) = mac.body {
+                                // on its own line, so that a trailing line comment does not hide the brace
+                                imports.push_str(&format!("{}\n}}", body));
+                            } else {
+                                imports.push_str("}\n");
+                            }
+                        }
                     }
                 }
             }
@@ -0,0 +1,357 @@
+use base64;
+use parity_wasm;
+use parity_wasm::elements::{ Module, Internal };
+
+use std::fs::File;
+use std::io::{ self, Write };
+use std::path::PathBuf;
+
+use PostProcessData;
+
+/// Generates the JavaScript that loads a wasm binary processed by `postprocess_crate`, either as a
+/// self-contained HTML page, as a script to load alongside the wasm binary, as an ES module or as
+/// a CommonJS or ES module for Node.js.
+///
+/// The generated page and script run the javascript from `PostProcessData::included`, instantiate
+/// the module with `PostProcessData::imports`, publish the module's instance, memory, exports and
+/// function table as globals on `window` and then call the entry point, if one is set, once the
+/// document has loaded. The included javascript runs inside a function scope, so any globals it
+/// defines should be assigned to `window`.
+///
+/// Example post-build script:
+///
+/// ```ignore
+/// extern crate embed_js_build;
+///
+/// fn main() {
+///     let pp_data = embed_js_build::postprocess_crate("my_crate", false).unwrap();
+///     embed_js_build::Loader::new()
+///         .entry_point("entry_point")
+///         .write_html(&pp_data)
+///         .unwrap();
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Loader {
+    entry_point: Option<String>,
+    instance_global: String,
+    memory_global: String,
+    exports_global: String,
+    table_global: String,
+}
+
+impl Default for Loader {
+    fn default() -> Loader {
+        Loader::new()
+    }
+}
+
+impl Loader {
+    /// Creates a loader with no entry point that publishes the globals `wasm_instance`,
+    /// `wasm_mem`, `wasm_exports` and `wasm_table`.
+    pub fn new() -> Loader {
+        Loader {
+            entry_point: None,
+            instance_global: String::from("wasm_instance"),
+            memory_global: String::from("wasm_mem"),
+            exports_global: String::from("wasm_exports"),
+            table_global: String::from("wasm_table"),
+        }
+    }
+
+    /// Sets the name of an exported function to call with no arguments once the module has loaded
+    /// and the document has been parsed.
+    pub fn entry_point(mut self, name: &str) -> Loader {
+        self.entry_point = Some(name.to_string());
+        self
+    }
+
+    /// Sets the name of the global for the `WebAssembly.Instance`.
+    pub fn instance_global(mut self, name: &str) -> Loader {
+        self.instance_global = name.to_string();
+        self
+    }
+
+    /// Sets the name of the global for the module's memory.
+    pub fn memory_global(mut self, name: &str) -> Loader {
+        self.memory_global = name.to_string();
+        self
+    }
+
+    /// Sets the name of the global for the module's exports.
+    pub fn exports_global(mut self, name: &str) -> Loader {
+        self.exports_global = name.to_string();
+        self
+    }
+
+    /// Sets the name of the global for the module's function table.
+    pub fn table_global(mut self, name: &str) -> Loader {
+        self.table_global = name.to_string();
+        self
+    }
+
+    /// Generates a self-contained HTML page with the wasm binary embedded in it as base64.
+    pub fn html(&self, data: &PostProcessData) -> String {
+        let title = data.wasm_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let instantiate = format!("WebAssembly.instantiate(embed_js_decode(\"{}\"), embed_js_imports)", base64::encode(&data.wasm));
+        let script = format!("\
+function embed_js_decode(base64) {{
+    var binary_string = window.atob(base64);
+    var bytes = new Uint8Array(binary_string.length);
+    for (var i = 0; i < binary_string.length; ++i) {{
+        bytes[i] = binary_string.charCodeAt(i);
+    }}
+    return bytes.buffer;
+}}
+{}", self.generate(data, &instantiate));
+        // keep the javascript from closing the script element early
+        let script = script.replace("</script", "<\\/script");
+        format!("\
+<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>{}</title>
+<script>
+{}</script>
+</head>
+<body>
+</body>
+</html>
+", title, script)
+    }
+
+    /// Generates a script that fetches the wasm binary from `wasm_url` with
+    /// `WebAssembly.instantiateStreaming`, falling back to `WebAssembly.instantiate` in browsers
+    /// without it. A relative `wasm_url` is resolved against the URL of the script, taken from
+    /// `document.currentScript`. A script that is inlined in a page or run from a bundle has no URL
+    /// of its own, so its `wasm_url` is resolved against `document.baseURI` instead, and should be
+    /// absolute or relative to the page.
+    pub fn script(&self, data: &PostProcessData, wasm_url: &str) -> String {
+        let instantiate = format!("\
+(function(url) {{
+    if (typeof WebAssembly.instantiateStreaming === \"function\") {{
+        return WebAssembly.instantiateStreaming(fetch(url), embed_js_imports);
+    }}
+    return fetch(url).then(function(response) {{
+        return response.arrayBuffer();
+    }}).then(function(bytes) {{
+        return WebAssembly.instantiate(bytes, embed_js_imports);
+    }});
+}})(new URL({:?}, document.currentScript && document.currentScript.src || document.baseURI))", wasm_url);
+        self.generate(data, &instantiate)
+    }
+
+    /// Generates an ES module for use with bundlers or `<script type="module">`.
+    ///
+    /// The module's default export, also exported as `init`, is an async function `init(input)`
+    /// that loads the module and calls the entry point, if one is set, resolving to the module's
+    /// exports. `input` may be a URL, a `Request`, a `Response` or a promise of one, a
+    /// `BufferSource` or a `WebAssembly.Module`. If it is omitted, the wasm binary is fetched from
+    /// `wasm_url` resolved against `import.meta.url`, which bundlers treat as a reference to an
+    /// asset. Once loaded, the module's exports, instance and memory are also available as the
+    /// exports `exports`, `instance` and `memory`.
+    ///
+    /// The included javascript runs at the top level of the module rather than in a function
+    /// scope, in strict mode, and no globals are published on `window`.
+    pub fn es_module(&self, data: &PostProcessData, wasm_url: &str) -> String {
+        let entry_point = match self.entry_point {
+            Some(ref entry_point) => format!("\n    wasm_exports[{:?}]();", entry_point),
+            None => String::new(),
+        };
+        format!("\
+{}
+async function embed_js_instantiate(input) {{
+    if (typeof input === \"string\" || input instanceof URL || (typeof Request === \"function\" && input instanceof Request)) {{
+        input = fetch(input);
+    }}
+    input = await input;
+    if (input instanceof WebAssembly.Module) {{
+        return await WebAssembly.instantiate(input, embed_js_imports);
+    }}
+    if (typeof Response === \"function\" && input instanceof Response) {{
+        if (typeof WebAssembly.instantiateStreaming === \"function\") {{
+            return (await WebAssembly.instantiateStreaming(input, embed_js_imports)).instance;
+        }}
+        input = await input.arrayBuffer();
+    }}
+    return (await WebAssembly.instantiate(input, embed_js_imports)).instance;
+}}
+export default async function init(input) {{
+    if (input === undefined) {{
+        input = new URL({:?}, import.meta.url);
+    }}
+    wasm_instance = await embed_js_instantiate(input);
+    wasm_mem = wasm_instance.exports.memory;
+    wasm_exports = wasm_instance.exports;
+    wasm_table = wasm_instance.exports.__table;{}
+    return wasm_exports;
+}}
+export {{ init, wasm_exports as exports, wasm_instance as instance, wasm_mem as memory }};
+", glue(data), wasm_url, entry_point)
+    }
+
+    /// Generates a CommonJS module for Node.js that reads the wasm binary from `wasm_path`, relative
+    /// to the module's directory, and loads it synchronously, calling the entry point if one is set.
+    /// The module's exports are the wasm module's exports.
+    ///
+    /// The included javascript runs in the scope of the module, and no globals are published.
+    pub fn node_commonjs(&self, data: &PostProcessData, wasm_path: &str) -> String {
+        format!("\
+{}
+wasm_instance = new WebAssembly.Instance(new WebAssembly.Module(require(\"fs\").readFileSync(require(\"path\").join(__dirname, {:?}))), embed_js_imports);
+wasm_mem = wasm_instance.exports.memory;
+wasm_exports = wasm_instance.exports;
+wasm_table = wasm_instance.exports.__table;
+module.exports = wasm_exports;{}
+", glue(data), wasm_path, self.node_entry_point())
+    }
+
+    /// Generates an ES module for Node.js that reads the wasm binary from `wasm_url`, resolved
+    /// against `import.meta.url`, and loads it synchronously when imported, calling the entry point
+    /// if one is set. The wasm module's exported functions are exported under their own names, and
+    /// its exports, instance and memory as `default` or `exports`, `instance` and `memory`.
+    ///
+    /// The included javascript runs at the top level of the module, in strict mode, and no globals
+    /// are published.
+    pub fn node_es_module(&self, data: &PostProcessData, wasm_url: &str) -> String {
+        let mut functions = String::new();
+        for (i, name) in exported_functions(data).iter().enumerate() {
+            functions.push_str(&format!("var embed_js_export_{0} = wasm_exports[{1:?}];\nexport {{ embed_js_export_{0} as {1} }};\n", i, name));
+        }
+        format!("\
+import {{ readFileSync }} from \"fs\";
+{}
+wasm_instance = new WebAssembly.Instance(new WebAssembly.Module(readFileSync(new URL({:?}, import.meta.url))), embed_js_imports);
+wasm_mem = wasm_instance.exports.memory;
+wasm_exports = wasm_instance.exports;
+wasm_table = wasm_instance.exports.__table;{}
+export default wasm_exports;
+export {{ wasm_exports as exports, wasm_instance as instance, wasm_mem as memory }};
+{}", glue(data), wasm_url, self.node_entry_point(), functions)
+    }
+
+    /// Writes the page from `html` next to the wasm binary, with the extension `html`. Returns the
+    /// path of the page.
+    pub fn write_html(&self, data: &PostProcessData) -> io::Result<PathBuf> {
+        let path = data.wasm_path.with_extension("html");
+        File::create(&path)?.write_all(self.html(data).as_bytes())?;
+        Ok(path)
+    }
+
+    /// Writes the script from `script` next to the wasm binary, with the extension `js`, loading
+    /// the wasm binary from the same directory. Returns the path of the script.
+    pub fn write_script(&self, data: &PostProcessData) -> io::Result<PathBuf> {
+        let path = data.wasm_path.with_extension("js");
+        let wasm_url = data.wasm_path.file_name().unwrap().to_string_lossy().into_owned();
+        File::create(&path)?.write_all(self.script(data, &wasm_url).as_bytes())?;
+        Ok(path)
+    }
+
+    /// Writes the module from `es_module` next to the wasm binary, with the extension `mjs`, loading
+    /// the wasm binary from the same directory by default. Returns the path of the module.
+    pub fn write_es_module(&self, data: &PostProcessData) -> io::Result<PathBuf> {
+        let path = data.wasm_path.with_extension("mjs");
+        let wasm_url = format!("./{}", data.wasm_path.file_name().unwrap().to_string_lossy());
+        File::create(&path)?.write_all(self.es_module(data, &wasm_url).as_bytes())?;
+        Ok(path)
+    }
+
+    /// Writes the module from `node_commonjs` next to the wasm binary, with the extension `cjs`,
+    /// loading the wasm binary from the same directory. Returns the path of the module.
+    pub fn write_node_commonjs(&self, data: &PostProcessData) -> io::Result<PathBuf> {
+        let path = data.wasm_path.with_extension("cjs");
+        let wasm_path = data.wasm_path.file_name().unwrap().to_string_lossy().into_owned();
+        File::create(&path)?.write_all(self.node_commonjs(data, &wasm_path).as_bytes())?;
+        Ok(path)
+    }
+
+    /// Writes the module from `node_es_module` next to the wasm binary, with the extension
+    /// `node.mjs`, loading the wasm binary from the same directory. Returns the path of the module.
+    pub fn write_node_es_module(&self, data: &PostProcessData) -> io::Result<PathBuf> {
+        let path = data.wasm_path.with_extension("node.mjs");
+        let wasm_url = format!("./{}", data.wasm_path.file_name().unwrap().to_string_lossy());
+        File::create(&path)?.write_all(self.node_es_module(data, &wasm_url).as_bytes())?;
+        Ok(path)
+    }
+
+    fn node_entry_point(&self) -> String {
+        match self.entry_point {
+            Some(ref entry_point) => format!("\nwasm_exports[{:?}]();", entry_point),
+            None => String::new(),
+        }
+    }
+
+    /// Generates the loader around `instantiate`, an expression for a promise of the results of
+    /// instantiating the module with `embed_js_imports`.
+    fn generate(&self, data: &PostProcessData, instantiate: &str) -> String {
+        let entry_point = match self.entry_point {
+            Some(ref entry_point) => format!("
+    var entry_point = function() {{
+        wasm_exports[{0:?}]();
+    }};
+    if (document.readyState === \"loading\") {{
+        document.addEventListener(\"DOMContentLoaded\", entry_point);
+    }} else {{
+        entry_point();
+    }}", entry_point),
+            None => String::new(),
+        };
+        format!("\
+(function() {{
+{}
+{}.then(function(results) {{
+    wasm_instance = results.instance;
+    wasm_mem = wasm_instance.exports.memory;
+    wasm_exports = wasm_instance.exports;
+    wasm_table = wasm_instance.exports.__table;
+    window[{:?}] = wasm_instance;
+    window[{:?}] = wasm_mem;
+    window[{:?}] = wasm_exports;
+    window[{:?}] = wasm_table;{}
+}});
+}})();
+",
+            glue(data),
+            instantiate,
+            self.instance_global,
+            self.memory_global,
+            self.exports_global,
+            self.table_global,
+            entry_point)
+    }
+}
+
+/// The declarations of the variables used by the generated imports, followed by the included
+/// javascript and the import object, `embed_js_imports`.
+fn glue(data: &PostProcessData) -> String {
+    format!("\
+var wasm_instance, wasm_mem, wasm_exports, wasm_table;
+{}
+var embed_js_imports = {{ env: {{
+{}
+}}}};", data.included, data.imports)
+}
+
+/// The names of the functions exported by the wasm binary that can be used as the names of ES
+/// module exports, leaving out those used by the generated modules and those from `embed_js`
+/// itself.
+fn exported_functions(data: &PostProcessData) -> Vec<String> {
+    let module: Module = parity_wasm::deserialize_buffer(data.wasm.clone()).unwrap();
+    let mut names = Vec::new();
+    if let Some(exports) = module.export_section() {
+        for entry in exports.entries() {
+            if let Internal::Function(_) = *entry.internal() {
+                let name = entry.field();
+                let valid = name.chars().enumerate().all(|(i, c)| {
+                    c == '_' || c == '$' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit())
+                });
+                if !name.is_empty() && valid && !name.starts_with("__embed_js") &&
+                    !["default", "exports", "instance", "memory"].contains(&name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
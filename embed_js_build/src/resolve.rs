@@ -0,0 +1,117 @@
+//! Working out which macro the path of a macro call refers to, following `use` declarations.
+//!
+//! Only as much of name resolution as is needed to recognise the macros `preprocess_crate` acts on
+//! is done. A macro called by a single name that is neither imported nor defined in the crate is
+//! assumed to come from `#[macro_use] extern crate embed_js` or the standard prelude.
+
+use syn::{ Ident, Path, UseTree, UseName, UseRename };
+use syn::ext::IdentExt;
+
+use std::collections::{ BTreeSet, HashMap };
+
+/// The macros that `preprocess_crate` acts on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Macro {
+    Js,
+    IncludeJs,
+    IncludeJsFile,
+    /// The standard `include!`.
+    Include,
+}
+
+impl Macro {
+    /// The macro exported by `embed_js` as `name`.
+    fn from_embed_js(name: &str) -> Option<Macro> {
+        match name {
+            "js" => Some(Macro::Js),
+            "include_js" => Some(Macro::IncludeJs),
+            "include_js_file" => Some(Macro::IncludeJsFile),
+            _ => None,
+        }
+    }
+}
+
+/// The names brought into scope in a module by `use` declarations.
+#[derive(Clone, Debug, Default)]
+pub struct Scope {
+    /// Each name brought into scope, with the path it refers to.
+    uses: HashMap<String, Vec<String>>,
+    /// The paths whose contents are all brought into scope.
+    globs: Vec<Vec<String>>,
+}
+
+fn segments(path: &Path) -> Vec<String> {
+    path.segments.iter().map(|segment| segment.ident.unraw().to_string()).collect()
+}
+
+impl Scope {
+    /// Brings the names imported by `use tree;` into scope.
+    pub fn add_use(&mut self, tree: &UseTree) {
+        self.add_use_tree(Vec::new(), tree);
+    }
+
+    fn add_use_tree(&mut self, mut prefix: Vec<String>, tree: &UseTree) {
+        match *tree {
+            UseTree::Path(ref path) => {
+                prefix.push(path.ident.unraw().to_string());
+                self.add_use_tree(prefix, &path.tree);
+            }
+            UseTree::Name(UseName { ref ident }) => self.import(prefix, ident, ident),
+            UseTree::Rename(UseRename { ref ident, ref rename, .. }) => self.import(prefix, ident, rename),
+            UseTree::Glob(_) => self.globs.push(prefix),
+            UseTree::Group(ref group) => {
+                for tree in &group.items {
+                    self.add_use_tree(prefix.clone(), tree);
+                }
+            }
+        }
+    }
+
+    /// Brings `ident` from the path `prefix` into scope as `name`.
+    fn import(&mut self, mut target: Vec<String>, ident: &Ident, name: &Ident) {
+        // `self` in a group imports the path of the group
+        if ident != "self" {
+            target.push(ident.unraw().to_string());
+        }
+        let name = if name == "self" {
+            match target.last() {
+                Some(name) => name.clone(),
+                None => return,
+            }
+        } else {
+            name.unraw().to_string()
+        };
+        self.uses.insert(name, target);
+    }
+
+    /// The macro that a call to `path` refers to, if it is one that `preprocess_crate` acts on.
+    /// `crates` are the names the `embed_js` crate is known by, and `local_macros` the names of
+    /// macros defined with `macro_rules!` that are in scope.
+    pub fn resolve(&self, path: &Path, crates: &BTreeSet<String>, local_macros: &BTreeSet<String>) -> Option<Macro> {
+        let mut segments = segments(path);
+        if path.leading_colon.is_none() {
+            if let Some(target) = self.uses.get(&segments[0]) {
+                segments.splice(0..1, target.iter().cloned());
+            } else if segments.len() == 1 {
+                let name = &segments[0];
+                if local_macros.contains(name) {
+                    return None;
+                }
+                if self.globs.iter().any(|glob| glob.len() == 1 && crates.contains(&glob[0])) {
+                    if let Some(found) = Macro::from_embed_js(name) {
+                        return Some(found);
+                    }
+                }
+                return match name.as_str() {
+                    "include" => Some(Macro::Include),
+                    name => Macro::from_embed_js(name),
+                };
+            }
+        }
+        match segments.len() {
+            2 if crates.contains(&segments[0]) => Macro::from_embed_js(&segments[1]),
+            2 if (segments[0] == "std" || segments[0] == "core") && segments[1] == "include" => Some(Macro::Include),
+            _ => None,
+        }
+    }
+}
@@ -1,16 +1,59 @@
-extern crate cpp_synmap;
 extern crate cpp_syn;
 #[macro_use]
 extern crate quote;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
 
-use cpp_synmap::SourceMap;
-use cpp_syn::{TokenTree, Delimited, DelimToken, Token, Span, BinOpToken};
+use cpp_syn::{TokenTree, Delimited, DelimToken, Token, Span, BinOpToken, Lit, LitKind};
+use sha2::{ Digest, Sha256 };
 
+use std::error::Error;
+use std::fmt;
+use std::io;
 use std::iter::Peekable;
 
+/// The name of the custom wasm section in which `embed_js_derive` records every `js` macro call it
+/// expands, as a sequence of newline-terminated JSON serializations of `JsMac`.
+pub const JS_MACS_SECTION: &str = "__embed_js_macs";
+
+/// The prefix of the names of the wasm imports generated for `js` macro calls.
+pub const IMPORT_PREFIX: &str = "__embed_js__";
+
+/// `IMPORT_PREFIX` followed by the first `len` bytes of the SHA-256 digest of the canonical
+/// serialization of `js_mac`, in lowercase hexadecimal.
+fn digest_name(js_mac: &JsMac, len: usize) -> String {
+    let digest = Sha256::digest(serde_json::to_vec(js_mac).unwrap());
+    let mut name = String::from(IMPORT_PREFIX);
+    for byte in &digest[..len] {
+        name.push_str(&format!("{:02x}", byte));
+    }
+    name
+}
+
+/// The link name of the wasm import through which the code generated for `js_mac` calls its body,
+/// in the binary built by cargo.
+///
+/// The name is made from the whole SHA-256 digest of the canonical serialization of `js_mac`, as
+/// described for `import_name`, so that different calls are linked to different imports. The call
+/// for an import is found by matching its name against the calls recorded in `JS_MACS_SECTION`.
+pub fn link_name(js_mac: &JsMac) -> String {
+    digest_name(js_mac, 32)
+}
+
+/// The preferred name of the wasm import for `js_mac` in the binary written by `postprocess_crate`.
+///
+/// The name is `IMPORT_PREFIX` followed by the first 16 bytes of the SHA-256 digest of the canonical
+/// serialization of `js_mac`, in lowercase hexadecimal. The canonical serialization is the compact
+/// JSON serialization of `JsMac` produced by `serde_json`, the same as the records in
+/// `JS_MACS_SECTION` without their trailing newlines. The name only depends on the contents of the
+/// call, so it is the same whichever toolchain or machine it is computed with.
+pub fn import_name(js_mac: &JsMac) -> String {
+    digest_name(js_mac, 16)
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub enum WasmPrimitiveType {
     I32,
@@ -19,115 +62,516 @@ pub enum WasmPrimitiveType {
     F64,
 }
 
-fn parse_wasm_primitive_type<'a, I>(iter: &mut Peekable<I>) -> Result<WasmPrimitiveType, ()>
+/// A Rust type that is passed to and from JavaScript as a single WebAssembly value.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+pub enum PrimitiveType {
+    I32,
+    I64,
+    F32,
+    F64,
+    /// `bool`, a JavaScript boolean.
+    Bool,
+    I8,
+    I16,
+    U8,
+    U16,
+    /// `u32`, a non-negative JavaScript number.
+    U32,
+    Isize,
+    /// `usize`, a non-negative JavaScript number.
+    Usize,
+    /// `char`, a JavaScript string of one code point.
+    Char,
+    /// `*const T` or `*mut T`, with whether it is `*mut` and the tokens of `T`. A non-negative
+    /// JavaScript number, the address in the wasm memory.
+    Pointer(bool, String),
+}
+
+impl PrimitiveType {
+    /// The WebAssembly type that values of this type are passed as.
+    pub fn wasm_type(&self) -> WasmPrimitiveType {
+        match *self {
+            PrimitiveType::I64 => WasmPrimitiveType::I64,
+            PrimitiveType::F32 => WasmPrimitiveType::F32,
+            PrimitiveType::F64 => WasmPrimitiveType::F64,
+            _ => WasmPrimitiveType::I32,
+        }
+    }
+}
+
+/// A syntax error encountered while parsing the contents of a `js` macro call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// What the parser was expecting at the point of failure.
+    pub expected: &'static str,
+    /// The offending token as written, or `None` if the input ended too early.
+    pub found: Option<String>,
+    /// The span of the offending token. If the input ended too early, this is the span of the
+    /// enclosing group or of the last token seen.
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.found {
+            Some(ref found) => write!(f, "expected {}, found `{}`", self.expected, found),
+            None => write!(f, "expected {}, found end of input", self.expected),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+fn unexpected(expected: &'static str, found: Option<&TokenTree>, end: Span) -> ParseError {
+    match found {
+        Some(tt) => ParseError {
+            expected,
+            found: Some(quote!(#tt).to_string()),
+            span: tt.span(),
+        },
+        None => ParseError {
+            expected,
+            found: None,
+            span: end,
+        },
+    }
+}
+
+const EXPECTED_TYPE: &str = "one of `i32`, `i64`, `f32`, `f64`, `bool`, `i8`, `i16`, `u8`, `u16`, `u32`, \
+                             `isize`, `usize`, `char`, `*const T` or `*mut T`";
+
+const EXPECTED_SIZED_POINTEE: &str = "a sized type to point to, not `str`, a slice or a trait object \
+                                      (strings are passed as `name: &str` and slices as `name: &[T]`)";
+
+fn parse_primitive_type<'a, I>(iter: &mut Peekable<I>, end: Span) -> Result<PrimitiveType, ParseError>
     where
         I: Iterator<Item = &'a TokenTree>,
 {
-    match iter.next() {
+    let next = iter.next();
+    match next {
         Some(&TokenTree::Token(Token::Ident(ref ident), _)) => {
             match ident.as_ref() {
-                "i32" => Ok(WasmPrimitiveType::I32),
-                "i64" => Ok(WasmPrimitiveType::I64),
-                "f32" => Ok(WasmPrimitiveType::F32),
-                "f64" => Ok(WasmPrimitiveType::F64),
-                _ => Err(()),
+                "i32" => Ok(PrimitiveType::I32),
+                "i64" => Ok(PrimitiveType::I64),
+                "f32" => Ok(PrimitiveType::F32),
+                "f64" => Ok(PrimitiveType::F64),
+                "bool" => Ok(PrimitiveType::Bool),
+                "i8" => Ok(PrimitiveType::I8),
+                "i16" => Ok(PrimitiveType::I16),
+                "u8" => Ok(PrimitiveType::U8),
+                "u16" => Ok(PrimitiveType::U16),
+                "u32" => Ok(PrimitiveType::U32),
+                "isize" => Ok(PrimitiveType::Isize),
+                "usize" => Ok(PrimitiveType::Usize),
+                "char" => Ok(PrimitiveType::Char),
+                _ => Err(unexpected(EXPECTED_TYPE, next, end)),
+            }
+        }
+        Some(&TokenTree::Token(Token::BinOp(BinOpToken::Star), _)) => {
+            let next = iter.next();
+            let mutable = match next {
+                Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "mut" => true,
+                Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "const" => false,
+                _ => return Err(unexpected("`const` or `mut`", next, end)),
+            };
+            // the pointee type runs up to the end of the argument or the start of the body
+            let mut pointee = Vec::new();
+            while let Some(&tt) = iter.peek() {
+                match *tt {
+                    TokenTree::Delimited(Delimited { delim: DelimToken::Brace, .. }, _) |
+                    TokenTree::Token(Token::Literal(_), _) => break,
+                    TokenTree::Token(Token::Ident(ref ident), _) if ident.as_ref() == "include_str" => break,
+                    _ => pointee.push(iter.next().unwrap()),
+                }
+            }
+            if pointee.is_empty() {
+                return Err(unexpected("the type pointed to", iter.peek().cloned(), end));
+            }
+            // pointers to unsized types are two words wide, which wasm cannot pass as one value
+            let unsized_pointee = match *pointee[0] {
+                TokenTree::Token(Token::Ident(ref ident), _) => {
+                    (ident.as_ref() == "str" && pointee.len() == 1) || ident.as_ref() == "dyn"
+                }
+                TokenTree::Delimited(Delimited { delim: DelimToken::Bracket, ref tts }, _) => {
+                    !tts.iter().any(|tt| matches!(*tt, TokenTree::Token(Token::Semi, _)))
+                }
+                _ => false,
+            };
+            if unsized_pointee {
+                return Err(unexpected(EXPECTED_SIZED_POINTEE, Some(pointee[0]), end));
+            }
+            Ok(PrimitiveType::Pointer(mutable, quote!(#(#pointee)*).to_string()))
+        }
+        _ => Err(unexpected(EXPECTED_TYPE, next, end)),
+    }
+}
+
+const EXPECTED_AS_OR_COLON: &str = "`as` followed by an argument type, `: &str`, `: &[T]`, `: &mut [T]`, `: JsRef` or `: Closure`";
+
+const EXPECTED_ELEMENT_TYPE: &str = "one of `i8`, `u8`, `i16`, `u16`, `i32`, `u32`, `isize`, `usize`, `i64`, `f32` or `f64`";
+
+/// Parses the type of an argument named by `name`, written as `name: type`, which is either a
+/// string (`&str` or `String`), a slice (`&[T]` or `&mut [T]`), a `JsRef` or a `Closure`.
+fn parse_named_arg_type<'a, I>(iter: &mut Peekable<I>, name: Span, end: Span) -> Result<SpanJsMacArg, ParseError>
+    where
+        I: Iterator<Item = &'a TokenTree>,
+{
+    let next = iter.next();
+    match next {
+        Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "String" => Ok(SpanJsMacArg::Str(name)),
+        // a `Closure` is passed as the `JsRef` of its JS function
+        Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "JsRef" || ident.as_ref() == "Closure" => Ok(SpanJsMacArg::JsRef(name)),
+        Some(&TokenTree::Token(Token::BinOp(BinOpToken::And), _)) => {
+            let mut next = iter.next();
+            let mutable = match next {
+                Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "mut" => {
+                    next = iter.next();
+                    true
+                }
+                _ => false,
+            };
+            match next {
+                Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "str" && !mutable => Ok(SpanJsMacArg::Str(name)),
+                Some(&TokenTree::Delimited(Delimited { delim: DelimToken::Bracket, ref tts }, span)) => {
+                    let mut elements = tts.iter().peekable();
+                    let ty = parse_primitive_type(&mut elements, span)
+                        .map_err(|e| ParseError { expected: EXPECTED_ELEMENT_TYPE, ..e })?;
+                    match ty {
+                        PrimitiveType::Bool | PrimitiveType::Char | PrimitiveType::Pointer(..) => {
+                            Err(unexpected(EXPECTED_ELEMENT_TYPE, tts.first(), span))
+                        }
+                        _ => match elements.next() {
+                            None => Ok(SpanJsMacArg::Slice(name, mutable, ty)),
+                            other => Err(unexpected("`]`", other, span)),
+                        },
+                    }
+                }
+                _ if mutable => Err(unexpected("`[`", next, end)),
+                _ => Err(unexpected("`str`, `[` or `mut`", next, end)),
+            }
+        }
+        _ => Err(unexpected("`&str`, `String`, `&[T]`, `&mut [T]`, `JsRef` or `Closure`", next, end)),
+    }
+}
+
+const EXPECTED_RETURN_TYPE: &str = "one of `i32`, `i64`, `f32`, `f64`, `bool`, `i8`, `i16`, `u8`, `u16`, `u32`, \
+                                    `isize`, `usize`, `char`, `*const T`, `*mut T`, `String`, `Vec<u8>` or `JsRef`";
+
+fn parse_return_type<'a, I>(iter: &mut Peekable<I>, end: Span) -> Result<JsMacRet, ParseError>
+    where
+        I: Iterator<Item = &'a TokenTree>,
+{
+    match iter.peek() {
+        Some(&&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "String" => {
+            iter.next();
+            Ok(JsMacRet::String)
+        }
+        Some(&&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "Vec" => {
+            iter.next();
+            for expected in &["<", "u8", ">"] {
+                let next = iter.next();
+                match (next, *expected) {
+                    (Some(&TokenTree::Token(Token::Lt, _)), "<") |
+                    (Some(&TokenTree::Token(Token::Gt, _)), ">") => {}
+                    (Some(&TokenTree::Token(Token::Ident(ref ident), _)), "u8") if ident.as_ref() == "u8" => {}
+                    _ => return Err(unexpected("`Vec<u8>`", next, end)),
+                }
             }
+            Ok(JsMacRet::Bytes)
+        }
+        Some(&&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "JsRef" => {
+            iter.next();
+            Ok(JsMacRet::JsRef)
+        }
+        _ => {
+            parse_primitive_type(iter, end)
+                .map(JsMacRet::Primitive)
+                .map_err(|e| ParseError { expected: EXPECTED_RETURN_TYPE, ..e })
         }
-        _ => Err(()),
     }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum JsMacArg {
     Ref(Vec<bool>, usize, String),
-    Primitive(usize, String, WasmPrimitiveType)
+    Primitive(usize, String, PrimitiveType),
+    Str(String),
+    /// An `embed_js::JsRef`, passed to JavaScript as the value it refers to.
+    JsRef(String),
+    /// A slice, with whether it is `&mut` and its element type, passed to JavaScript as a typed
+    /// array viewing the wasm memory.
+    Slice(String, bool, PrimitiveType),
+}
+
+/// The return type of a `js` macro call.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JsMacRet {
+    Primitive(PrimitiveType),
+    /// `String`, returned from JavaScript as a string.
+    String,
+    /// `Vec<u8>`, returned from JavaScript as anything a `Uint8Array` can be constructed from.
+    Bytes,
+    /// `embed_js::JsRef`, returned from JavaScript as any value.
+    JsRef,
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct JsMac {
     pub args: Vec<JsMacArg>,
-    pub ret: Option<WasmPrimitiveType>,
+    pub ret: Option<JsMacRet>,
     pub body: Option<String>,
 }
 
 enum SpanJsMacArg {
     Ref(Vec<bool>, usize, Span),
-    Primitive(usize, Span, WasmPrimitiveType)
+    Primitive(usize, Span, PrimitiveType),
+    Str(Span),
+    JsRef(Span),
+    Slice(Span, bool, PrimitiveType),
+}
+
+enum SpanJsBody {
+    /// The span of the body's source text, between its braces.
+    Source(Span),
+    /// The contents of a string literal body, used as they are.
+    Literal(String),
+    /// The path given to `include_str!` for a body read from a file, with the span of the path.
+    File(String, Span),
+}
+
+impl SpanJsBody {
+    fn into_text<F, G>(self, source_text: F, read_file: G) -> Result<String, ParseError>
+        where
+            F: FnOnce(Span) -> String,
+            G: FnOnce(&str, Span) -> io::Result<String>,
+    {
+        match self {
+            SpanJsBody::Source(span) => Ok(source_text(span)),
+            SpanJsBody::Literal(text) => Ok(text),
+            SpanJsBody::File(path, span) => {
+                read_file(&path, span).map_err(|e| ParseError {
+                    expected: "the path of a readable file",
+                    found: Some(format!("{:?} ({})", path, e)),
+                    span,
+                })
+            }
+        }
+    }
+}
+
+/// Parses `include_str!("path")`, returning the path and its span.
+fn parse_include_str<'a, I>(iter: &mut Peekable<I>, end: Span) -> Result<(String, Span), ParseError>
+    where
+        I: Iterator<Item = &'a TokenTree>,
+{
+    iter.next(); // `include_str`
+    let next = iter.next();
+    match next {
+        Some(&TokenTree::Token(Token::Not, _)) => {}
+        _ => return Err(unexpected("`!`", next, end)),
+    }
+    let next = iter.next();
+    match next {
+        Some(&TokenTree::Delimited(Delimited { delim: DelimToken::Paren, ref tts }, span)) => {
+            match tts.first() {
+                Some(&TokenTree::Token(Token::Literal(Lit { node: LitKind::Str(ref path, _), .. }), path_span)) if tts.len() == 1 => {
+                    Ok((path.clone(), path_span))
+                }
+                other => Err(unexpected("a string literal path", other.or(tts.get(1)), span)),
+            }
+        }
+        _ => Err(unexpected("`(`", next, end)),
+    }
+}
+
+/// Words that cannot name a parameter of the generated JavaScript functions, including in strict
+/// mode, which applies to the ES module loaders.
+const JS_RESERVED_WORDS: &[&str] = &[
+    "arguments", "await", "break", "case", "catch", "class", "const", "continue", "debugger",
+    "default", "delete", "do", "else", "enum", "eval", "export", "extends", "false", "finally",
+    "for", "function", "if", "implements", "import", "in", "instanceof", "interface", "let", "new",
+    "null", "package", "private", "protected", "public", "return", "static", "super", "switch",
+    "this", "throw", "true", "try", "typeof", "var", "void", "while", "with", "yield",
+];
+
+const EXPECTED_JS_NAME: &str = "a name that is not reserved in JavaScript \
+                                (a value can be passed under another name as `expr => name`)";
+
+/// Completes an argument name that starts with the identifier `ident` at `span`. A raw identifier
+/// such as `r#type` is lexed as `r`, `#` and `type`, which are joined back into one span. The
+/// JavaScript sees the name without the `r#`, so it must not be reserved there.
+fn parse_name<'a, I>(ident: &str, span: Span, iter: &mut Peekable<I>) -> Result<Span, ParseError>
+    where
+        I: Iterator<Item = &'a TokenTree> + Clone,
+{
+    let mut name = ident.to_string();
+    let mut span = span;
+    if ident == "r" {
+        let mut ahead = iter.clone();
+        if let (Some(&TokenTree::Token(Token::Pound, pound)), Some(&TokenTree::Token(Token::Ident(ref raw), raw_span))) = (ahead.next(), ahead.next()) {
+            if span.hi == pound.lo && pound.hi == raw_span.lo {
+                *iter = ahead;
+                name = format!("r#{}", raw.as_ref());
+                span.hi = raw_span.hi;
+            }
+        }
+    }
+    if JS_RESERVED_WORDS.contains(&name.strip_prefix("r#").unwrap_or(&name)) {
+        return Err(ParseError {
+            expected: EXPECTED_JS_NAME,
+            found: Some(name),
+            span,
+        });
+    }
+    Ok(span)
+}
+
+/// Splits the tokens of an argument list at its commas, except for those between the angle
+/// brackets of generic arguments, as in `f::<u8, u16>()` or `<T as Trait>::f()`. A `<` opens
+/// generic arguments after `::`, at the start of an argument or inside other generic arguments,
+/// and is a comparison anywhere else.
+fn split_args(tts: &[TokenTree]) -> Vec<&[TokenTree]> {
+    let mut args = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    for (i, tt) in tts.iter().enumerate() {
+        let opens = depth > 0 || i == start || matches!(tts[i - 1], TokenTree::Token(Token::ModSep, _));
+        match *tt {
+            TokenTree::Token(Token::Comma, _) if depth == 0 => {
+                args.push(&tts[start..i]);
+                start = i + 1;
+            }
+            TokenTree::Token(Token::Lt, _) if opens => depth += 1,
+            TokenTree::Token(Token::BinOp(BinOpToken::Shl), _) if opens => depth += 2,
+            TokenTree::Token(Token::Gt, _) if depth > 0 => depth -= 1,
+            TokenTree::Token(Token::BinOp(BinOpToken::Shr), _) if depth > 0 => depth -= depth.min(2),
+            _ => {}
+        }
+    }
+    args.push(&tts[start..]);
+    args
+}
+
+/// Parses one argument of a `js` macro call from the tokens between commas. `end` is the span of
+/// the argument list.
+fn parse_arg(tts: &[TokenTree], end: Span) -> Result<SpanJsMacArg, ParseError> {
+    if let Some(arrow) = tts.iter().position(|tt| matches!(*tt, TokenTree::Token(Token::FatArrow, _))) {
+        return parse_expr_arg(&tts[..arrow], &tts[arrow], &tts[arrow + 1..], end);
+    }
+    let mut iter = tts.iter().peekable();
+    let name;
+    let mut derefs = 0;
+    let mut refs = Vec::new();
+    loop {
+        match iter.next() {
+            Some(&TokenTree::Token(Token::BinOp(BinOpToken::And), _)) => {
+                if let Some(&&TokenTree::Token(Token::Ident(ref ident), _)) = iter.peek() {
+                    if ident.as_ref() == "mut" {
+                        iter.next();
+                        refs.push(true);
+                    } else {
+                        refs.push(false);
+                    }
+                } else {
+                    refs.push(false);
+                }
+            }
+            Some(&TokenTree::Token(Token::BinOp(BinOpToken::Star), _)) => {
+                derefs += 1;
+            }
+            Some(&TokenTree::Token(Token::Ident(ref ident), span)) => {
+                name = parse_name(ident.as_ref(), span, &mut iter)?;
+                break;
+            },
+            other => return Err(unexpected("an argument name, optionally preceded by `&`, `&mut` or `*`, or an expression followed by `=>`", other, end))
+        }
+    }
+    let arg = if !refs.is_empty() {
+        SpanJsMacArg::Ref(refs, derefs, name)
+    } else {
+        match iter.next() {
+            Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "as" => {
+                SpanJsMacArg::Primitive(derefs, name, parse_primitive_type(&mut iter, end)?)
+            }
+            Some(&TokenTree::Token(Token::Colon, _)) if derefs == 0 => {
+                parse_named_arg_type(&mut iter, name, end)?
+            }
+            other => return Err(unexpected(if derefs == 0 { EXPECTED_AS_OR_COLON } else { "`as` followed by an argument type" }, other, end)),
+        }
+    };
+    match iter.next() {
+        None => Ok(arg),
+        other => Err(unexpected("`,` or `]`", other, end)),
+    }
+}
+
+/// Parses an argument written as `expr => name as type`, `expr => name: type` or `&expr => name`,
+/// which passes the value of the Rust expression `expr` to the JavaScript as `name`, from the
+/// tokens of `expr`, the `=>` and the rest of the argument.
+fn parse_expr_arg(expr: &[TokenTree], arrow: &TokenTree, rest: &[TokenTree], end: Span) -> Result<SpanJsMacArg, ParseError> {
+    if expr.is_empty() {
+        return Err(unexpected("an expression before `=>`", Some(arrow), end));
+    }
+    let mut iter = rest.iter().peekable();
+    let next = iter.next();
+    let name = match next {
+        Some(&TokenTree::Token(Token::Ident(ref ident), span)) => parse_name(ident.as_ref(), span, &mut iter)?,
+        _ => return Err(unexpected("an argument name after `=>`", next, arrow.span())),
+    };
+    let arg = match iter.next() {
+        Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "as" => {
+            SpanJsMacArg::Primitive(0, name, parse_primitive_type(&mut iter, end)?)
+        }
+        Some(&TokenTree::Token(Token::Colon, _)) => parse_named_arg_type(&mut iter, name, end)?,
+        None => {
+            // a reference is passed as a pointer
+            match (&expr[0], expr.get(1)) {
+                (&TokenTree::Token(Token::BinOp(BinOpToken::And), _), Some(&TokenTree::Token(Token::Ident(ref ident), _))) if ident.as_ref() == "mut" => {
+                    SpanJsMacArg::Ref(vec![true], 0, name)
+                }
+                (&TokenTree::Token(Token::BinOp(BinOpToken::And), _), _) | (&TokenTree::Token(Token::AndAnd, _), _) => {
+                    SpanJsMacArg::Ref(vec![false], 0, name)
+                }
+                _ => return Err(unexpected(EXPECTED_AS_OR_COLON, None, name)),
+            }
+        }
+        other => return Err(unexpected(EXPECTED_AS_OR_COLON, other, end)),
+    };
+    match iter.next() {
+        None => Ok(arg),
+        other => Err(unexpected("`,` or `]`", other, end)),
+    }
 }
 
 struct SpanJsMac {
     args: Vec<SpanJsMacArg>,
-    ret: Option<WasmPrimitiveType>,
-    body: Option<String>,
+    ret: Option<JsMacRet>,
+    body: Option<SpanJsBody>,
 }
 
-fn parse_js_mac_span(tts: &[TokenTree]) -> Result<SpanJsMac, ()> {
+const EXPECTED_START: &str = "`[`, `{`, a string literal or `include_str!`";
+
+fn parse_js_mac_span(tts: &[TokenTree]) -> Result<SpanJsMac, ParseError> {
+    let end = tts.last().map(|tt| tt.span()).unwrap_or(Span { lo: 0, hi: 0 });
     let mut iter = tts.iter().peekable();
-    let mut args;
+    let args;
     let ret;
     match iter.peek() {
-        Some(&&TokenTree::Delimited(Delimited { delim, ref tts }, _)) => {
+        Some(&&TokenTree::Delimited(Delimited { delim, ref tts }, span)) => {
             match delim {
                 DelimToken::Bracket => {
                     iter.next(); // consume
-                    args = Vec::new();
-                    {
-                        let mut iter = tts.iter().peekable();
-                        let mut start = true;
-                        loop {
-                            if start {
-                                start = false;
-                            } else {
-                                match iter.next() {
-                                    Some(&TokenTree::Token(Token::Comma, _)) => {},
-                                    None => break,
-                                    _ => return Err(()),
-                                }
-                            }
-                            if iter.peek().is_none() {
-                                break;
-                            }
-                            let name;
-                            let mut derefs = 0;
-                            let mut refs = Vec::new();
-                            loop {
-                                match iter.next() {
-                                    Some(&TokenTree::Token(Token::BinOp(BinOpToken::And), _)) => {
-                                        if let Some(&&TokenTree::Token(Token::Ident(ref ident), _)) = iter.peek() {
-                                            if ident.as_ref() == "mut" {
-                                                iter.next();
-                                                refs.push(true);
-                                            } else {
-                                                refs.push(false);
-                                            }
-                                        } else {
-                                            refs.push(false);
-                                        }
-                                    }
-                                    Some(&TokenTree::Token(Token::BinOp(BinOpToken::Star), _)) => {
-                                        derefs += 1;
-                                    }
-                                    Some(&TokenTree::Token(Token::Ident(_), span)) => {
-                                        name = span;
-                                        break;
-                                    },
-                                    _ => return Err(())
-                                }
-                            }
-                            if refs.len() > 0 {
-                                args.push(SpanJsMacArg::Ref(refs, derefs, name));
-                            } else {
-                                match iter.next() {
-                                    Some(&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "as" => {}
-                                    _ => return Err(()),
-                                }
-                                args.push(SpanJsMacArg::Primitive(derefs, name, parse_wasm_primitive_type(&mut iter)?));
-                            }
-                        }
+                    // arguments are separated by commas, optionally with one after the last
+                    let mut arg_tts = split_args(tts);
+                    if arg_tts.last().is_some_and(|arg_tts| arg_tts.is_empty()) {
+                        arg_tts.pop();
                     }
+                    args = arg_tts.into_iter()
+                        .map(|arg_tts| parse_arg(arg_tts, span))
+                        .collect::<Result<_, _>>()?;
                     ret = if let Some(&&TokenTree::Token(Token::RArrow, _)) = iter.peek() {
                         iter.next();
-                        Some(parse_wasm_primitive_type(&mut iter)?)
+                        Some(parse_return_type(&mut iter, end)?)
                     } else {
                         None
                     };
@@ -136,55 +580,66 @@ fn parse_js_mac_span(tts: &[TokenTree]) -> Result<SpanJsMac, ()> {
                     args = vec![];
                     ret = None;
                 },
-                _ => return Err(()),
+                _ => return Err(unexpected(EXPECTED_START, iter.next(), end)),
             }
         }
-        _ => return Err(()),
+        Some(&&TokenTree::Token(Token::Literal(_), _)) => { // no params or return
+            args = vec![];
+            ret = None;
+        }
+        Some(&&TokenTree::Token(Token::Ident(ref ident), _)) if ident.as_ref() == "include_str" => {
+            args = vec![];
+            ret = None;
+        }
+        _ => return Err(unexpected(EXPECTED_START, iter.next(), end)),
     }
 
+    if let Some(&&TokenTree::Token(Token::Ident(ref ident), _)) = iter.peek() {
+        if ident.as_ref() == "include_str" {
+            let (path, span) = parse_include_str(&mut iter, end)?;
+            return match iter.next() {
+                None => Ok(SpanJsMac {
+                    args,
+                    ret,
+                    body: Some(SpanJsBody::File(path, span)),
+                }),
+                other => Err(unexpected("the end of the macro input", other, end))
+            };
+        }
+    }
     let result = match iter.next() {
         Some(&TokenTree::Delimited(Delimited {
                                        delim: DelimToken::Brace,
                                        ref tts,
                                    },
-                                   _)) => {
+                                   span)) => {
+            SpanJsMac {
+                args,
+                ret,
+                body: if !tts.is_empty() { Some(SpanJsBody::Source(Span { lo: span.lo + 1, hi: span.hi - 1 })) } else { None },
+            }
+        }
+        Some(&TokenTree::Token(Token::Literal(Lit { node: LitKind::Str(ref text, _), .. }), _)) => {
             SpanJsMac {
                 args,
                 ret,
-                body: if tts.len() > 0 { Some(quote!(#(#tts)*).to_string()) } else { None },
+                body: if !text.is_empty() { Some(SpanJsBody::Literal(text.clone())) } else { None },
             }
         }
-        _ => return Err(()),
+        other => return Err(unexpected(if ret.is_some() { "`{`, a string literal or `include_str!`" } else { "`->`, `{`, a string literal or `include_str!`" }, other, end)),
     };
-    match iter.peek() {
+    match iter.next() {
         None => Ok(result),
-        Some(_) => Err(())
+        other => Err(unexpected("the end of the macro input", other, end))
     }
 }
 
-pub fn parse_js_mac_source_map(tts: &[TokenTree], source_map: &SourceMap) -> Result<JsMac, ()> {
-    let spanned = parse_js_mac_span(tts)?;
-    Ok(JsMac {
-        args: spanned
-            .args
-            .into_iter()
-            .map(|arg| {
-                match arg {
-                    SpanJsMacArg::Ref(refs, derefs, span) => {
-                        JsMacArg::Ref(refs, derefs, source_map.source_text(span).unwrap().to_string())
-                    }
-                    SpanJsMacArg::Primitive(derefs, span, t) => {
-                        JsMacArg::Primitive(derefs, source_map.source_text(span).unwrap().to_string(), t)
-                    }
-                }
-            })
-            .collect(),
-        ret: spanned.ret,
-        body: spanned.body,
-    })
-}
-
-pub fn parse_js_mac_string_source(tts: &[TokenTree], string_source: &str) -> Result<JsMac, ()> {
+/// Parses a `js` macro call whose tokens were parsed from `string_source`. `read_file` reads the
+/// file named by the path and its span for a body given as `include_str!("path")`.
+pub fn parse_js_mac_string_source<F>(tts: &[TokenTree], string_source: &str, read_file: F) -> Result<JsMac, ParseError>
+    where
+        F: FnOnce(&str, Span) -> io::Result<String>,
+{
     let spanned = parse_js_mac_span(tts)?;
     Ok(JsMac {
         args: spanned
@@ -198,10 +653,21 @@ pub fn parse_js_mac_string_source(tts: &[TokenTree], string_source: &str) -> Res
                     SpanJsMacArg::Primitive(derefs, span, t) => {
                         JsMacArg::Primitive(derefs, string_source[span.lo..span.hi].to_string(), t)
                     }
+                    SpanJsMacArg::Str(span) => {
+                        JsMacArg::Str(string_source[span.lo..span.hi].to_string())
+                    }
+                    SpanJsMacArg::JsRef(span) => {
+                        JsMacArg::JsRef(string_source[span.lo..span.hi].to_string())
+                    }
+                    SpanJsMacArg::Slice(span, mutable, t) => {
+                        JsMacArg::Slice(string_source[span.lo..span.hi].to_string(), mutable, t)
+                    }
                 }
             })
             .collect(),
         ret: spanned.ret,
-        body: spanned.body,
+        body: spanned.body
+            .map(|body| body.into_text(|span| string_source[span.lo..span.hi].to_string(), read_file))
+            .transpose()?,
     })
 }
\ No newline at end of file
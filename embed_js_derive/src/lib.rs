@@ -2,13 +2,17 @@ extern crate proc_macro;
 
 extern crate cpp_syn;
 extern crate embed_js_common;
+extern crate serde_json;
 #[macro_use] extern crate quote;
 use cpp_syn::{ TokenTree, Ident };
 
-use proc_macro::TokenStream;
-use embed_js_common::{ WasmPrimitiveType, JsMacArg };
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use proc_macro::{ TokenStream, Span, Delimiter, Spacing, Literal, Punct, Group };
+use embed_js_common::{ WasmPrimitiveType, PrimitiveType, JsMac, JsMacArg, JsMacRet, ParseError };
+use std::ascii;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 
 fn unwrap_delimited(t: &TokenTree) -> &[TokenTree] {
     match *t {
@@ -17,6 +21,162 @@ fn unwrap_delimited(t: &TokenTree) -> &[TokenTree] {
     }
 }
 
+/// Writes the tokens of `stream` out as source text that `cpp_syn` can parse, recording the offset
+/// of each token in the text along with its original span so that errors can be reported against
+/// the user's code.
+fn stringify_with_spans(stream: TokenStream, out: &mut String, spans: &mut Vec<(usize, Span)>) {
+    for token in stream {
+        spans.push((out.len(), token.span()));
+        match token {
+            proc_macro::TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                out.push_str(open);
+                out.push(' ');
+                stringify_with_spans(group.stream(), out, spans);
+                out.push_str(close);
+                out.push(' ');
+            }
+            proc_macro::TokenTree::Punct(punct) => {
+                out.push(punct.as_char());
+                if punct.spacing() == Spacing::Alone {
+                    out.push(' ');
+                }
+            }
+            proc_macro::TokenTree::Ident(ident) => {
+                out.push_str(&ident.to_string());
+                out.push(' ');
+            }
+            proc_macro::TokenTree::Literal(literal) => {
+                out.push_str(&literal.to_string());
+                out.push(' ');
+            }
+        }
+    }
+}
+
+/// Finds the span of the token that starts at or most recently before `offset` in the text
+/// produced by `stringify_with_spans`.
+fn span_at(spans: &[(usize, Span)], offset: usize) -> Span {
+    match spans.binary_search_by_key(&offset, |&(start, _)| start) {
+        Ok(i) => spans[i].1,
+        Err(0) => Span::call_site(),
+        Err(i) => spans[i - 1].1,
+    }
+}
+
+/// Builds `compile_error!(...);` for a malformed `js` macro call, pointing at the offending tokens.
+fn syntax_error(error: &ParseError, span: Span) -> TokenStream {
+    let message = format!(
+        "syntax error in js macro: {}\n\
+         help: the accepted forms are `js!([arg, ...] -> type {{ ... }})`, `js!([arg, ...] {{ ... }})` and `js!({{ ... }})`\n\
+         the braced body may also be a string literal such as `r#\"...\"#` or `include_str!(\"file.js\")`\n\
+         each argument is one of\n\
+         - `name as type`, optionally preceded by any number of `*`\n\
+         - `name: &str` or `name: String`\n\
+         - `name: &[f32]`, `name: &mut [u8]` or another slice of numbers\n\
+         - `name: JsRef` or `name: Closure`\n\
+         - `&name`, `&mut name` or `&**name`\n\
+         - `expr => name as type`, `expr => name: &str` or another of the forms above after `=>`\n\
+         - `&expr => name` or `&mut expr => name`\n\
+         types are `i32`, `i64`, `f32`, `f64`, `bool`, `i8`, `i16`, `u8`, `u16`, `u32`, `isize`, `usize`, `char`, \
+         `*const T` or `*mut T`\n\
+         return types may also be `String`, `Vec<u8>` or `JsRef`",
+        error
+    );
+    let mut args = Literal::string(&message);
+    args.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, proc_macro::TokenTree::Literal(args).into());
+    group.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut semi = Punct::new(';', Spacing::Alone);
+    semi.set_span(span);
+    vec![
+        proc_macro::TokenTree::Ident(proc_macro::Ident::new("compile_error", span)),
+        proc_macro::TokenTree::Punct(bang),
+        proc_macro::TokenTree::Group(group),
+        proc_macro::TokenTree::Punct(semi),
+    ].into_iter().collect()
+}
+
+/// Generates a `call` function and an `__embed_js_invoke` macro that stand in for those of a
+/// malformed `js` macro call. The arguments are dropped without being parsed as expressions, so
+/// that the only error reported is the one from `syntax_error`.
+fn call_stub() -> TokenStream {
+    let result = quote! {
+        impl EmbedJsStruct {
+            fn call() -> ! {
+                unreachable!()
+            }
+        }
+        macro_rules! __embed_js_invoke {
+            ($($args:tt)*) => { EmbedJsStruct::call() }
+        }
+    };
+    result.parse().unwrap()
+}
+
+/// The text between the braces of the body of a `js` macro call as written in the user's source,
+/// including comments and the original layout. Returns `None` if the body was not written in a
+/// source file as it is, such as when it was put together by another macro.
+fn original_body(body: &TokenTree, spans: &[(usize, Span)]) -> Option<String> {
+    if let TokenTree::Token(..) = *body {
+        // a string literal body is used as it is
+        return None
+    }
+    let text = span_at(spans, body.span().lo).source_text()?;
+    if !text.starts_with('{') || !text.ends_with('}') {
+        return None
+    }
+    // the text of a body written inside a macro definition may not match the tokens passed in
+    let written = cpp_syn::parse_token_trees(&text).ok()?;
+    let written = unwrap_delimited(written.first()?);
+    let passed = unwrap_delimited(body);
+    if quote!(#(#written)*).to_string() != quote!(#(#passed)*).to_string() {
+        return None
+    }
+    Some(text[1..text.len() - 1].to_string())
+}
+
+/// The path of the file named by `path` in `include_str!(path)` as the body of a `js` macro call,
+/// relative to the file containing the call at `span` like `include_str!` itself.
+fn body_file_path(path: &str, span: Span) -> io::Result<PathBuf> {
+    let mut path = PathBuf::from(path);
+    if path.is_relative() {
+        let file = span.local_file()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the file containing the call is unknown"))?;
+        path = env::current_dir()?.join(file.parent().unwrap_or(&file)).join(path);
+    }
+    Ok(path)
+}
+
+/// Generates a static that places `js_mac` in a custom section of the final wasm binary, for
+/// `postprocess_crate` to find.
+fn js_mac_static(js_mac: &JsMac) -> quote::Tokens {
+    let mut json = serde_json::to_vec(js_mac).unwrap();
+    json.push(b'\n');
+    let len = json.len();
+    let mut literal = String::from("*b\"");
+    for &byte in &json {
+        literal.extend(ascii::escape_default(byte).map(char::from));
+    }
+    literal.push('"');
+    let mut bytes = quote::Tokens::new();
+    bytes.append(&literal);
+    let section = embed_js_common::JS_MACS_SECTION;
+    quote! {
+        #[cfg(target_arch = "wasm32")]
+        #[link_section = #section]
+        #[used]
+        static EMBED_JS_MAC: [u8; #len] = #bytes;
+    }
+}
+
 fn prim_to_ty(ty: WasmPrimitiveType) -> Ident {
     match ty {
         WasmPrimitiveType::I32 => Ident::from("i32"),
@@ -26,30 +186,103 @@ fn prim_to_ty(ty: WasmPrimitiveType) -> Ident {
     }
 }
 
+/// The Rust type `ty`, as written in the generated code.
+fn rust_ty(ty: &PrimitiveType) -> quote::Tokens {
+    let name = match *ty {
+        PrimitiveType::I32 => "i32",
+        PrimitiveType::I64 => "i64",
+        PrimitiveType::F32 => "f32",
+        PrimitiveType::F64 => "f64",
+        PrimitiveType::Bool => "bool",
+        PrimitiveType::I8 => "i8",
+        PrimitiveType::I16 => "i16",
+        PrimitiveType::U8 => "u8",
+        PrimitiveType::U16 => "u16",
+        PrimitiveType::U32 => "u32",
+        PrimitiveType::Isize => "isize",
+        PrimitiveType::Usize => "usize",
+        PrimitiveType::Char => "char",
+        PrimitiveType::Pointer(mutable, ref pointee) => {
+            let pointee = Ident::from(pointee.as_str());
+            return if mutable { quote!(*mut #pointee) } else { quote!(*const #pointee) };
+        }
+    };
+    let name = Ident::from(name);
+    quote!(#name)
+}
+
+/// Converts `value`, of the WebAssembly type that `ty` is passed as, back to `ty`.
+fn from_wasm(ty: &PrimitiveType, value: quote::Tokens) -> quote::Tokens {
+    match *ty {
+        PrimitiveType::I32 | PrimitiveType::I64 | PrimitiveType::F32 | PrimitiveType::F64 => value,
+        PrimitiveType::Bool => quote!(#value != 0),
+        PrimitiveType::Char => quote!(::std::char::from_u32(#value as u32).unwrap_or('\u{fffd}')),
+        PrimitiveType::Pointer(..) => {
+            let ty = rust_ty(ty);
+            quote!(#value as u32 as usize as #ty)
+        }
+        _ => {
+            let ty = rust_ty(ty);
+            quote!(#value as #ty)
+        }
+    }
+}
+
 #[proc_macro_derive(EmbedJsDetail)]
 pub fn embed_js(input: TokenStream) -> TokenStream {
-    let s: String = input.to_string();
+    let mut s = String::new();
+    let mut spans = Vec::new();
+    stringify_with_spans(input, &mut s, &mut spans);
     let tokens = cpp_syn::parse_token_trees(&s).unwrap();
     let trimmed = unwrap_delimited(&unwrap_delimited(&unwrap_delimited(&tokens[4])[2])[2]);
-    let js_mac = embed_js_common::parse_js_mac_string_source(trimmed, &s).expect("syntax error in js macro");
-    let mut hasher = DefaultHasher::new();
-    js_mac.hash(&mut hasher);
-    let mac_hash = hasher.finish();
+    let mut body_file = None;
+    let parsed = embed_js_common::parse_js_mac_string_source(trimmed, &s, |path, span| {
+        let path = body_file_path(path, span_at(&spans, span.lo))?;
+        let body = fs::read_to_string(&path)?;
+        body_file = Some(path);
+        Ok(body)
+    });
+    let mut js_mac = match parsed {
+        Ok(js_mac) => js_mac,
+        Err(error) => {
+            let mut result = syntax_error(&error, span_at(&spans, error.span.lo));
+            result.extend(call_stub());
+            return result
+        }
+    };
+    if js_mac.body.is_some() {
+        if let Some(body) = trimmed.last().and_then(|body| original_body(body, &spans)) {
+            js_mac.body = Some(body);
+        }
+    }
+    // makes the crate be rebuilt when a body read from a file changes
+    let body_file = body_file.map(|path| {
+        let path = path.to_string_lossy().into_owned();
+        quote!(const _: &[u8] = include_bytes!(#path);)
+    });
+    // the call is recorded in the binary for postprocess_crate, which finds its import by name
+    let js_mac_static = js_mac_static(&js_mac);
+    let link_name = embed_js_common::link_name(&js_mac);
+    let extern_name = Ident::from("embed_js_import");
     let mut type_params = Vec::new();
     let mut arg_names = Vec::new();
     let mut arg_types = Vec::new();
     let mut extern_arg_types = Vec::new();
-    let mut cast_fragments = Vec::new();
+    let mut extern_args = Vec::new();
     let mut next_type_param = 0;
     for arg in js_mac.args {
         match arg {
             JsMacArg::Primitive(_, name, ty) => {
-                arg_names.push(Ident::from(name));
-                let ty = prim_to_ty(ty);
-                let ty_ = ty.clone();
-                extern_arg_types.push(quote!(#ty_));
-                cast_fragments.push(quote!());
-                arg_types.push(quote!(#ty));
+                let name = Ident::from(name);
+                let wasm_ty = prim_to_ty(ty.wasm_type());
+                if rust_ty(&ty).as_str() == wasm_ty.as_ref() {
+                    extern_args.push(quote!(#name));
+                } else {
+                    extern_args.push(quote!(#name as #wasm_ty));
+                }
+                extern_arg_types.push(quote!(#wasm_ty));
+                arg_types.push(rust_ty(&ty));
+                arg_names.push(name);
             }
             JsMacArg::Ref(refs, _, name) => {
                 let mutable = refs[0];
@@ -58,7 +291,7 @@ pub fn embed_js(input: TokenStream) -> TokenStream {
                 } else {
                     quote!()
                 };
-                arg_names.push(Ident::from(name));
+                let name = Ident::from(name);
                 let type_param = Ident::from(format!("T{}", next_type_param));
                 {
                     let type_param_ = &type_param;
@@ -66,49 +299,109 @@ pub fn embed_js(input: TokenStream) -> TokenStream {
                     extern_arg_types.push(quote!(*mut u8));
                     arg_types.push(quote!(& #mutability #type_param_));
                     if mutable {
-                        cast_fragments.push(quote!(as *mut #type_param_ as *mut u8));
+                        extern_args.push(quote!(#name as *mut #type_param_ as *mut u8));
                     } else {
-                        cast_fragments.push(quote!(as *const #type_param_ as *const u8 as *mut u8));
+                        extern_args.push(quote!(#name as *const #type_param_ as *const u8 as *mut u8));
                     }
                 }
                 type_params.push(type_param);
+                arg_names.push(name);
+            }
+            JsMacArg::Str(name) => {
+                // passed as a pointer and a length, to be decoded on the JS side
+                let name = Ident::from(name);
+                extern_arg_types.push(quote!(*const u8));
+                extern_arg_types.push(quote!(usize));
+                extern_args.push(quote!(#name.as_ptr()));
+                extern_args.push(quote!(#name.len()));
+                arg_types.push(quote!(&str));
+                arg_names.push(name);
+            }
+            JsMacArg::Slice(name, mutable, ty) => {
+                // passed as a pointer and a length, to be viewed as a typed array on the JS side
+                let name = Ident::from(name);
+                let elem_ty = rust_ty(&ty);
+                if mutable {
+                    extern_arg_types.push(quote!(*mut #elem_ty));
+                    extern_args.push(quote!(#name.as_mut_ptr()));
+                    arg_types.push(quote!(&mut [#elem_ty]));
+                } else {
+                    extern_arg_types.push(quote!(*const #elem_ty));
+                    extern_args.push(quote!(#name.as_ptr()));
+                    arg_types.push(quote!(&[#elem_ty]));
+                }
+                extern_arg_types.push(quote!(usize));
+                extern_args.push(quote!(#name.len()));
+                arg_names.push(name);
+            }
+            JsMacArg::JsRef(name) => {
+                // passed as its index in the JS heap table, looked up on the JS side
+                let name = Ident::from(name);
+                extern_arg_types.push(quote!(u32));
+                extern_args.push(quote!(#name.as_raw()));
+                arg_types.push(quote!(&EmbedJsRef));
+                arg_names.push(name);
             }
         }
     }
-    let type_params = if type_params.len() == 0 {
+    let type_params = if type_params.is_empty() {
         quote!()
     } else {
         quote!(<#(#type_params),*>)
     };
+    let (ret_ty, extern_ret_ty, body) = match js_mac.ret {
+        None => (quote!(), quote!(), quote!(#extern_name(#(#extern_args),*))),
+        Some(JsMacRet::Primitive(ref ty)) => {
+            let rust_ty = rust_ty(ty);
+            let wasm_ty = prim_to_ty(ty.wasm_type());
+            (quote!(-> #rust_ty), quote!(-> #wasm_ty), from_wasm(ty, quote!(#extern_name(#(#extern_args),*))))
+        }
+        Some(JsMacRet::JsRef) => {
+            (quote!(-> EmbedJsRef), quote!(-> u32), quote!(EmbedJsRef::from_raw(#extern_name(#(#extern_args),*))))
+        }
+        Some(ret) => {
+            // the JS side allocates the result with __embed_js_alloc and writes its length through
+            // an extra pointer argument
+            extern_arg_types.push(quote!(*mut usize));
+            extern_args.push(quote!(&mut __ret_len));
+            let ret_value = if ret == JsMacRet::String {
+                quote!(String::from_utf8_unchecked(__ret_bytes))
+            } else {
+                quote!(__ret_bytes)
+            };
+            let ret_ty = if ret == JsMacRet::String {
+                quote!(String)
+            } else {
+                quote!(Vec<u8>)
+            };
+            (quote!(-> #ret_ty), quote!(-> *mut u8), quote! {
+                let mut __ret_len = 0usize;
+                let __ret_ptr = #extern_name(#(#extern_args),*);
+                let __ret_bytes = Vec::from_raw_parts(__ret_ptr, __ret_len, __ret_len);
+                #ret_value
+            })
+        }
+    };
     let arg_names = &arg_names;
     let arg_types = &arg_types;
-    let extern_name = Ident::from(format!("__embed_js__{:x}", mac_hash));
-    let result = match js_mac.ret {
-        Some(ty) => {
-            let ty = prim_to_ty(ty);
-            quote! {
-                impl EmbedJsStruct {
-                    fn call #type_params(#(#arg_names: #arg_types),*) -> #ty {
-                        extern {
-                            fn #extern_name(#(#arg_names: #extern_arg_types),*) -> #ty;
-                        }
-                        unsafe { #extern_name(#(#arg_names #cast_fragments),*) }
-                    }
-                }
-            }
-        },
-        None => {
-            quote! {
-                impl EmbedJsStruct {
-                    fn call #type_params(#(#arg_names: #arg_types),*) {
-                        extern {
-                            fn #extern_name(#(#arg_names: #extern_arg_types),*);
-                        }
-                        unsafe { #extern_name(#(#arg_names #cast_fragments),*) }
-                    }
+    let extern_arg_types = &extern_arg_types;
+    let result = quote! {
+        #body_file
+        #js_mac_static
+        impl EmbedJsStruct {
+            #[allow(clippy::too_many_arguments)]
+            fn call #type_params(#(#arg_names: #arg_types),*) #ret_ty {
+                extern {
+                    #[link_name = #link_name]
+                    fn #extern_name(#(_: #extern_arg_types),*) #extern_ret_ty;
                 }
+                unsafe { #body }
             }
         }
+        // used by `__embed_js_call` once it has converted the arguments
+        macro_rules! __embed_js_invoke {
+            ($($args:tt)*) => { EmbedJsStruct::call($($args)*) }
+        }
     };
     result.parse().unwrap()
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+#[macro_use]
+extern crate embed_js;
 extern crate subcrate;
 
 pub use subcrate::*;
@@ -6,3 +8,11 @@ pub use subcrate::*;
 pub fn add_two(x: i32) -> i32 {
     subcrate::add_one(subcrate::add_one(x))
 }
+
+#[no_mangle]
+pub fn entry_point() {
+    let result = add_two(2);
+    js!([result as i32] {
+        console.log(result);
+    });
+}